@@ -0,0 +1,14 @@
+#![no_main]
+
+use learndns::dns::packet::{BytePacketBuffer, Packet, MAX_TCP_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+// Parsing arbitrary bytes must never panic or hang, and whatever parses
+// must be writable again.
+fuzz_target!(|data: &[u8]| {
+    let mut packet_buf = BytePacketBuffer::from_bytes(data);
+    if let Ok(packet) = Packet::from_buf(&mut packet_buf) {
+        let mut res_packet_buf = BytePacketBuffer::with_max_size(MAX_TCP_SIZE);
+        let _ = packet.write(&mut res_packet_buf);
+    }
+});
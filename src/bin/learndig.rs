@@ -0,0 +1,288 @@
+use learndns::dns::error::DnsError;
+use learndns::dns::name::Name;
+use learndns::dns::packet::Packet;
+use learndns::dns::record::QueryType;
+use learndns::dns::server::{Server, DEFAULT_LOOKUP_DEADLINE};
+use learndns::dns::trace::Hop;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::process;
+use std::time::{Duration, Instant};
+
+const USAGE: &str =
+    "usage: learndig [@server] [-p port] [name] [TYPE] [+tcp] [+norec] [+trace] [+short]";
+
+/// What to ask, who to ask, and how to show the answer.
+#[derive(Debug, PartialEq)]
+struct Options {
+    server: Option<String>,
+    port: u16,
+    name: Name,
+    qtype: QueryType,
+    tcp: bool,
+    norec: bool,
+    trace: bool,
+    short: bool,
+}
+
+impl Options {
+    /// Reads the arguments the way dig does, in any order: with no name we
+    /// ask for the root's NS records, with no type for A records.
+    ///
+    /// `+trace` walks down from the root servers on its own, so it can't be
+    /// given `@server`, `-p` or `+tcp`.
+    fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut server = None;
+        let mut port = None;
+        let mut name = None;
+        let mut qtype = None;
+        let (mut tcp, mut norec, mut trace, mut short) = (false, false, false, false);
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some(host) = arg.strip_prefix('@') {
+                server = Some(host.to_string());
+            } else if arg == "-p" {
+                let value = args.next().ok_or("-p needs a port")?;
+                port = Some(value.parse().map_err(|_| format!("bad port {}", value))?);
+            } else if let Some(option) = arg.strip_prefix('+') {
+                match option {
+                    "tcp" => tcp = true,
+                    "norec" | "norecurse" => norec = true,
+                    "trace" => trace = true,
+                    "short" => short = true,
+                    _ => return Err(format!("unknown option +{}", option)),
+                }
+            } else if qtype.is_none() && arg.parse::<QueryType>().is_ok() {
+                qtype = arg.parse().ok();
+            } else if name.is_none() {
+                name = Some(arg.parse().map_err(|e| format!("{}", e))?);
+            } else {
+                return Err(format!("unexpected argument {}", arg));
+            }
+        }
+
+        if trace && (server.is_some() || port.is_some() || tcp) {
+            return Err("+trace can't be used with @server, -p or +tcp".to_string());
+        }
+
+        let qtype = qtype.unwrap_or(match name {
+            Some(_) => QueryType::A,
+            None => QueryType::NS,
+        });
+        Ok(Options {
+            server,
+            port: port.unwrap_or(53),
+            name: name.unwrap_or_else(Name::root),
+            qtype,
+            tcp,
+            norec,
+            trace,
+            short,
+        })
+    }
+}
+
+fn main() {
+    let options = Options::from_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("learndig: {}\n{}", e, USAGE);
+        process::exit(1);
+    });
+
+    let result = match options.trace {
+        true => trace(&options),
+        false => server_addr(&options).and_then(|server| query(&options, server)),
+    };
+    if let Err(e) = result {
+        eprintln!(";; communications error: {}", e);
+        process::exit(9);
+    }
+}
+
+/// The server from `@server`, or else the first nameserver in
+/// /etc/resolv.conf, or else localhost.
+fn server_addr(options: &Options) -> Result<(IpAddr, u16), DnsError> {
+    let host = match options.server.as_ref() {
+        Some(host) => host.clone(),
+        None => fs::read_to_string("/etc/resolv.conf")
+            .unwrap_or_default()
+            .lines()
+            .find_map(|e| e.strip_prefix("nameserver"))
+            .map(|e| e.trim().to_string())
+            .unwrap_or_else(|| Ipv4Addr::LOCALHOST.to_string()),
+    };
+
+    if let Ok(ip) = host.parse() {
+        return Ok((ip, options.port));
+    }
+    let addr = (host.as_str(), options.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or(DnsError::InvalidName(host))?;
+    Ok((addr.ip(), addr.port()))
+}
+
+/// Sends one query, over UDP unless `+tcp`, and TCP again if the answer
+/// was truncated. Gives the response, its size and how long it took.
+fn exchange(
+    options: &Options,
+    send_packet: &Packet,
+    server: (IpAddr, u16),
+) -> Result<(Packet, usize, Duration, &'static str), DnsError> {
+    let start = Instant::now();
+    if !options.tcp {
+        let (res_packet, size) = Server::exchange(send_packet, server, DEFAULT_LOOKUP_DEADLINE)?;
+        if !res_packet.header.truncated_message {
+            return Ok((res_packet, size, start.elapsed(), "UDP"));
+        }
+        if !options.short {
+            println!(";; Truncated, retrying in TCP mode.");
+        }
+    }
+
+    let start = Instant::now();
+    let (res_packet, size) = Server::exchange_tcp(send_packet, server, DEFAULT_LOOKUP_DEADLINE)?;
+    Ok((res_packet, size, start.elapsed(), "TCP"))
+}
+
+fn query_packet(options: &Options, name: &Name) -> Result<Packet, DnsError> {
+    let mut send_packet = Server::query_packet(name, options.qtype)?;
+    send_packet.header.recursion_desired = !options.norec;
+    Ok(send_packet)
+}
+
+fn query(options: &Options, server: (IpAddr, u16)) -> Result<(), DnsError> {
+    let send_packet = query_packet(options, &options.name)?;
+    let (res_packet, size, elapsed, proto) = exchange(options, &send_packet, server)?;
+
+    if options.short {
+        print_short(&res_packet);
+        return Ok(());
+    }
+
+    println!(
+        "; <<>> learndig <<>> {}",
+        env::args().skip(1).collect::<Vec<_>>().join(" ")
+    );
+    println!(";; Got answer:");
+    print!("{}", res_packet);
+    println!();
+    println!(";; Query time: {} msec", elapsed.as_millis());
+    println!(
+        ";; SERVER: {}#{}({}) ({})",
+        server.0, server.1, server.0, proto
+    );
+    println!(";; MSG SIZE  rcvd: {}", size);
+    println!();
+
+    Ok(())
+}
+
+fn print_short(res_packet: &Packet) {
+    for rec in res_packet.answers.iter() {
+        if let Some(rdata) = rec.rdata() {
+            println!("{}", rdata);
+        }
+    }
+}
+
+/// Resolves the name the way our server would, from the root hints down,
+/// and shows every hop it took on the way.
+fn trace(options: &Options) -> Result<(), DnsError> {
+    let (result, hops) = Server::new().trace_lookup(&options.name, options.qtype);
+    if !options.short {
+        print_hops(&hops, "");
+    }
+
+    let res_packet = result?;
+    match options.short {
+        true => print_short(&res_packet),
+        false => {
+            println!(";; Got answer:");
+            print!("{}", res_packet);
+        }
+    }
+    Ok(())
+}
+
+/// Prints `hops` like dig +trace, nameserver lookups of their own indented
+/// under the referral that needed them.
+fn print_hops(hops: &[Hop], indent: &str) {
+    for hop in hops.iter() {
+        match hop {
+            Hop::Query {
+                zone,
+                server,
+                rtt,
+                response: Ok((res_packet, size)),
+                ..
+            } => {
+                for rec in res_packet
+                    .answers
+                    .iter()
+                    .chain(res_packet.authorities.iter())
+                {
+                    println!("{}{}", indent, rec);
+                }
+                println!(
+                    "{};; Received {} bytes from {}#53({}) in {} ms",
+                    indent,
+                    size,
+                    server,
+                    zone,
+                    rtt.as_millis()
+                );
+                println!();
+            }
+            Hop::ResolveNs {
+                host, qtype, hops, ..
+            } => {
+                println!("{};; resolving NS {} {}", indent, host, qtype);
+                print_hops(hops, &format!("{}    ", indent));
+                println!("{};; {}", indent, hop);
+                println!();
+            }
+            _ => println!("{};; {}", indent, hop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn should_read_args_like_dig() {
+        let options =
+            Options::from_args(args("@8.8.8.8 -p 5353 google.com MX +tcp +short")).unwrap();
+
+        assert_eq!(options.server.as_deref(), Some("8.8.8.8"));
+        assert_eq!(options.port, 5353);
+        assert_eq!(options.name, "google.com");
+        assert_eq!(options.qtype, QueryType::MX);
+        assert!(options.tcp && options.short && !options.norec && !options.trace);
+
+        let options = Options::from_args(args("AAAA +norec example.com")).unwrap();
+        assert_eq!(options.name, "example.com");
+        assert_eq!(options.qtype, QueryType::AAAA);
+        assert!(options.norec);
+
+        let options = Options::from_args(Vec::new()).unwrap();
+        assert!(options.name.is_root());
+        assert_eq!(options.qtype, QueryType::NS);
+
+        assert!(Options::from_args(args("google.com +bogus")).is_err());
+        assert!(Options::from_args(args("-p lots")).is_err());
+
+        assert!(Options::from_args(args("google.com +trace")).unwrap().trace);
+        assert!(Options::from_args(args("@8.8.8.8 google.com +trace")).is_err());
+        assert!(Options::from_args(args("-p 5353 google.com +trace")).is_err());
+        assert!(Options::from_args(args("google.com +tcp +trace")).is_err());
+    }
+}
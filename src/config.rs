@@ -0,0 +1,462 @@
+use crate::dns::cache::DEFAULT_CACHE_SIZE;
+use crate::dns::hints::{self, HintsError};
+use crate::dns::name::Name;
+use crate::dns::server::{
+    Server, DEFAULT_LOOKUP_DEADLINE, DEFAULT_MAX_RECURSIONS, DEFAULT_QUERY_TIMEOUT,
+    DEFAULT_ROOT_HINTS,
+};
+use crate::dns::zone::{ParseError, Zone};
+use log::LevelFilter;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+pub const USAGE: &str = "usage: learndns [--config FILE] [--listen ADDR]... [--zone ORIGIN=FILE]...
+                [--root-hint IP]... [--root-hints-file FILE] [--cache-size N]
+                [--max-recursions N] [--query-timeout MS] [--lookup-timeout MS]
+                [--log-level LEVEL]";
+
+/// Server settings, read from a TOML file and then overridden from the
+/// command line. Anything left out keeps its default.
+///
+/// ```toml
+/// listen = ["0.0.0.0:53", "[::]:53"]
+/// root_hints = ["198.41.0.4", "2001:503:ba3e::2:30"]
+/// # or, in place of root_hints
+/// root_hints_file = "/etc/learndns/named.root"
+/// cache_size = 10000
+/// max_recursions = 100
+/// query_timeout_ms = 2000
+/// lookup_timeout_ms = 5000
+/// log_level = "info"
+///
+/// [[zones]]
+/// origin = "example.com"
+/// file = "/etc/learndns/example.com.zone"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to answer queries on, over both UDP and TCP.
+    pub listen: Vec<SocketAddr>,
+    /// Root servers recursion starts from.
+    pub root_hints: Vec<IpAddr>,
+    /// A `named.root` file to read the root servers from, in place of
+    /// `root_hints`.
+    pub root_hints_file: Option<String>,
+    /// How many RRsets the cache holds at most.
+    pub cache_size: usize,
+    /// How many recursive lookups may be under way at once.
+    pub max_recursions: usize,
+    /// How long we wait for one upstream server, in milliseconds.
+    pub query_timeout_ms: u64,
+    /// How long one client query may take all told, in milliseconds.
+    pub lookup_timeout_ms: u64,
+    /// One of off, error, warn, info, debug or trace.
+    pub log_level: LevelFilter,
+    /// Zones we answer for authoritatively, from master files.
+    pub zones: Vec<ZoneConfig>,
+}
+
+/// A zone to serve, and the master file its data is in.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    #[serde(deserialize_with = "deserialize_name")]
+    pub origin: Name,
+    pub file: String,
+}
+
+impl ZoneConfig {
+    /// Parses `ORIGIN=FILE`, as given to `--zone`.
+    fn from_arg(arg: &str) -> Option<ZoneConfig> {
+        let (origin, file) = arg.split_once('=')?;
+        Some(ZoneConfig {
+            origin: origin.parse().ok()?,
+            file: file.to_string(),
+        })
+    }
+}
+
+fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Name, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    text.parse()
+        .map_err(|e| de::Error::custom(format!("bad name {:?}: {}", text, e)))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Io(String, io::Error),
+    /// The config file isn't valid TOML, or has settings we don't know.
+    Parse(String, toml::de::Error),
+    /// The root hints file couldn't be read or parsed.
+    Hints(String, HintsError),
+    /// A zone's master file couldn't be read or parsed.
+    Zone(ParseError),
+    /// A command-line argument we don't understand.
+    Usage(String),
+    /// A setting with a value we can't run with.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "can't read config {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "bad config {}: {}", path, e),
+            ConfigError::Hints(path, e) => write!(f, "bad root hints {}: {}", path, e),
+            ConfigError::Zone(e) => write!(f, "bad zone file {}", e),
+            ConfigError::Usage(msg) if msg.is_empty() => write!(f, "{}", USAGE),
+            ConfigError::Usage(msg) => write!(f, "{}\n{}", msg, USAGE),
+            ConfigError::Invalid(msg) => write!(f, "invalid setting: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 4053))],
+            root_hints: DEFAULT_ROOT_HINTS.to_vec(),
+            root_hints_file: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+            max_recursions: DEFAULT_MAX_RECURSIONS,
+            query_timeout_ms: DEFAULT_QUERY_TIMEOUT.as_millis() as u64,
+            lookup_timeout_ms: DEFAULT_LOOKUP_DEADLINE.as_millis() as u64,
+            log_level: LevelFilter::Info,
+            zones: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`.
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+        Config::parse(&text).map_err(|e| match e {
+            ConfigError::Parse(_, e) => ConfigError::Parse(path.to_string(), e),
+            e => e,
+        })
+    }
+
+    /// Parses a config from TOML text, reading `root_hints_file` if it's
+    /// set.
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let table: toml::Table =
+            toml::from_str(text).map_err(|e| ConfigError::Parse(String::new(), e))?;
+        if table.contains_key("root_hints") && table.contains_key("root_hints_file") {
+            return Err(ConfigError::Invalid(
+                "root_hints and root_hints_file can't be used together".to_string(),
+            ));
+        }
+
+        let mut config: Config =
+            toml::from_str(text).map_err(|e| ConfigError::Parse(String::new(), e))?;
+        config.load_root_hints()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Replaces `root_hints` with the servers in `root_hints_file`, if set.
+    fn load_root_hints(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = self.root_hints_file.as_ref() {
+            self.root_hints = hints::load(path).map_err(|e| ConfigError::Hints(path.clone(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Builds the config from the command line: the file named by
+    /// `--config`, if any, then every other flag on top of it.
+    pub fn from_args<I>(args: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let args: Vec<String> = args.into_iter().collect();
+
+        let mut config = Config::default();
+        if let Some(i) = args.iter().position(|e| e == "--config") {
+            let path = args
+                .get(i + 1)
+                .ok_or_else(|| ConfigError::Usage("--config needs a value".to_string()))?;
+            config = Config::load(path)?;
+        }
+
+        // listen addresses, root hints and zones given on the command line
+        // replace the file's, rather than adding to them
+        let mut listen = Vec::new();
+        let mut root_hints = Vec::new();
+        let mut zones = Vec::new();
+        let mut root_hints_file = None;
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag)))
+            };
+            match flag.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--listen" => listen.push(parse_value(&flag, &value()?)?),
+                "--root-hint" => root_hints.push(parse_value(&flag, &value()?)?),
+                "--zone" => {
+                    let value = value()?;
+                    let zone = ZoneConfig::from_arg(&value).ok_or_else(|| {
+                        ConfigError::Invalid(format!("bad value {:?} for {}", value, flag))
+                    })?;
+                    zones.push(zone);
+                }
+                "--root-hints-file" => root_hints_file = Some(value()?),
+                "--cache-size" => config.cache_size = parse_value(&flag, &value()?)?,
+                "--max-recursions" => config.max_recursions = parse_value(&flag, &value()?)?,
+                "--query-timeout" => config.query_timeout_ms = parse_value(&flag, &value()?)?,
+                "--lookup-timeout" => config.lookup_timeout_ms = parse_value(&flag, &value()?)?,
+                "--log-level" => config.log_level = parse_value(&flag, &value()?)?,
+                "-h" | "--help" => return Err(ConfigError::Usage(String::new())),
+                _ => return Err(ConfigError::Usage(format!("unknown argument {}", flag))),
+            }
+        }
+
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        if !zones.is_empty() {
+            config.zones = zones;
+        }
+        if !root_hints.is_empty() && root_hints_file.is_some() {
+            return Err(ConfigError::Usage(
+                "--root-hint and --root-hints-file can't be used together".to_string(),
+            ));
+        }
+        if !root_hints.is_empty() {
+            config.root_hints = root_hints;
+            config.root_hints_file = None;
+        }
+        if root_hints_file.is_some() {
+            config.root_hints_file = root_hints_file;
+            config.load_root_hints()?;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks the settings make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.listen.is_empty() {
+            return invalid("listen needs at least one address");
+        }
+        if self.root_hints.is_empty() {
+            return invalid("root_hints needs at least one address");
+        }
+        if self.cache_size == 0 {
+            return invalid("cache_size must be more than 0");
+        }
+        if self.max_recursions == 0 {
+            return invalid("max_recursions must be more than 0");
+        }
+        if self.query_timeout_ms == 0 {
+            return invalid("query_timeout_ms must be more than 0");
+        }
+        if self.lookup_timeout_ms < self.query_timeout_ms {
+            return invalid("lookup_timeout_ms can't be less than query_timeout_ms");
+        }
+
+        Ok(())
+    }
+
+    /// A server set up with these settings, its zones loaded.
+    pub fn server(&self) -> Result<Server, ConfigError> {
+        let mut server = Server::with_cache_size(self.cache_size);
+        server.set_max_recursions(self.max_recursions);
+        server.set_root_hints(self.root_hints.clone());
+        server.set_query_timeout(Duration::from_millis(self.query_timeout_ms));
+        server.set_lookup_deadline(Duration::from_millis(self.lookup_timeout_ms));
+        for zone in self.zones.iter() {
+            let zone = Zone::load(&zone.file, zone.origin.clone()).map_err(ConfigError::Zone)?;
+            server.add_zone(zone);
+        }
+        Ok(server)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Invalid(format!("bad value {:?} for {}", value, flag)))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn should_parse_config() {
+        let config = Config::parse(
+            r#"
+            listen = ["127.0.0.1:53", "[::1]:53"]
+            root_hints = ["198.41.0.4", "2001:503:ba3e::2:30"]
+            cache_size = 500
+            query_timeout_ms = 1000
+            log_level = "debug"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.listen,
+            vec![
+                "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.root_hints.len(), 2);
+        assert!(config.zones.is_empty());
+        assert_eq!(config.cache_size, 500);
+        assert_eq!(config.query_timeout_ms, 1000);
+        assert_eq!(config.lookup_timeout_ms, 5000);
+        assert_eq!(config.log_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn should_reject_bad_config() {
+        assert!(matches!(
+            Config::parse("listen = [\"nowhere\"]"),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            Config::parse("cache_sise = 10"),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            Config::parse("root_hints = []"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::parse("query_timeout_ms = 6000"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::parse("[[zones]]\norigin = \"example..com\"\nfile = \"example.com.zone\""),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn should_override_from_args() {
+        let config = Config::from_args(args(
+            "--listen 127.0.0.1:5353 --listen [::1]:5353 --cache-size 20 --log-level warn",
+        ))
+        .unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.cache_size, 20);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.root_hints, DEFAULT_ROOT_HINTS.to_vec());
+
+        assert!(matches!(
+            Config::from_args(args("--cache-size lots")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--listen")),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--root-hints-file /no/such/named.root")),
+            Err(ConfigError::Hints(..))
+        ));
+        assert!(matches!(
+            Config::from_args(args(
+                "--root-hint 198.41.0.4 --root-hints-file /no/such/named.root"
+            )),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--zone example.com")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--zone example.com=/no/such.zone"))
+                .unwrap()
+                .server(),
+            Err(ConfigError::Zone(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--verbose")),
+            Err(ConfigError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn should_load_root_hints_file_from_config() {
+        let path = std::env::temp_dir().join(format!("learndns-hints-{}", std::process::id()));
+        fs::write(
+            &path,
+            ".  3600000  NS  A.ROOT-SERVERS.NET.\nA.ROOT-SERVERS.NET.  3600000  A  198.41.0.4\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Config::parse(&format!("root_hints_file = {:?}", path));
+        let both = Config::parse(&format!(
+            "root_hints = [\"192.0.2.1\"]\nroot_hints_file = {:?}",
+            path
+        ));
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            config.unwrap().root_hints,
+            vec!["198.41.0.4".parse::<IpAddr>().unwrap()]
+        );
+        assert!(matches!(both, Err(ConfigError::Invalid(_))));
+        assert!(matches!(
+            Config::parse("root_hints_file = \"/no/such/named.root\""),
+            Err(ConfigError::Hints(..))
+        ));
+    }
+
+    #[test]
+    fn should_replace_file_zones_from_args() {
+        let path = std::env::temp_dir().join(format!("learndns-config-{}", std::process::id()));
+        fs::write(
+            &path,
+            "[[zones]]\norigin = \"example.com\"\nfile = \"example.com.zone\"\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Config::from_args(args(&format!("--config {}", path))).unwrap();
+        assert_eq!(config.zones.len(), 1);
+        assert_eq!(config.zones[0].origin, "example.com");
+
+        let config = Config::from_args(args(&format!(
+            "--config {} --zone example.org=example.org.zone",
+            path
+        )))
+        .unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            config.zones,
+            [ZoneConfig {
+                origin: "example.org".parse().unwrap(),
+                file: "example.org.zone".to_string(),
+            }]
+        );
+    }
+}
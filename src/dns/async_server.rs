@@ -0,0 +1,434 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::Name;
+use crate::dns::packet::{Packet, MAX_EDNS_SIZE, MAX_TCP_SIZE, MAX_UDP_SIZE};
+use crate::dns::record::QueryType;
+use crate::dns::resolver::{Action, Resolution};
+use crate::dns::server::{self, Request, Server, TCP_IDLE_TIMEOUT};
+use crate::dns::trace::{Hop, Trace};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{watch, Semaphore};
+use tokio::time;
+
+type LookupResult = Option<Result<Packet, DnsError>>;
+
+/// How many TCP connections `serve_tcp` serves at once; it drops any more.
+pub const MAX_TCP_CONNECTIONS: usize = 256;
+
+/// The async front end of `Server`, on tokio. It shares the cache, and the
+/// packet handling, with the blocking one it wraps.
+pub struct AsyncServer {
+    server: Server,
+    /// Lookups under way, for queries asking the same question to wait on.
+    inflight: Mutex<HashMap<(Name, QueryType), watch::Receiver<LookupResult>>>,
+    recursions: Semaphore,
+}
+
+/// Takes a lookup out of `inflight` when it's done, or dropped half way.
+struct InflightGuard<'a> {
+    server: &'a AsyncServer,
+    key: (Name, QueryType),
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.server.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl AsyncServer {
+    pub fn new(server: Server) -> AsyncServer {
+        let recursions = Semaphore::new(server.max_recursions);
+        AsyncServer {
+            server,
+            inflight: Mutex::new(HashMap::new()),
+            recursions,
+        }
+    }
+
+    /// The blocking server underneath, sharing the same cache.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Sends one query over UDP from a random port and waits up to
+    /// `timeout` for the answer, dropping anything that doesn't match it,
+    /// like `Server::lookup`.
+    pub async fn lookup(
+        domain: &Name,
+        qtype: QueryType,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<Packet, DnsError> {
+        let send_packet = Server::query_packet(domain, qtype)?;
+        AsyncServer::exchange(&send_packet, server, timeout)
+            .await
+            .map(|e| e.0)
+    }
+
+    pub async fn lookup_tcp(
+        domain: &Name,
+        qtype: QueryType,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<Packet, DnsError> {
+        let send_packet = Server::query_packet(domain, qtype)?;
+        AsyncServer::exchange_tcp(&send_packet, server, timeout)
+            .await
+            .map(|e| e.0)
+    }
+
+    /// Sends `send_packet` over UDP like `lookup`, giving back the response
+    /// and its size in bytes.
+    pub async fn exchange(
+        send_packet: &Packet,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<(Packet, usize), DnsError> {
+        let send_data = server::encode(send_packet, MAX_UDP_SIZE)?;
+
+        let local = match server.0 {
+            IpAddr::V4(_) => "0.0.0.0",
+            IpAddr::V6(_) => "::",
+        };
+        let udp_socket = UdpSocket::bind((local, 0)).await?;
+        udp_socket.send_to(&send_data, server).await?;
+
+        let deadline = time::Instant::now() + timeout;
+        let mut rev_data = [0; MAX_EDNS_SIZE];
+        loop {
+            let (len, src) = time::timeout_at(deadline, udp_socket.recv_from(&mut rev_data))
+                .await
+                .map_err(|_| DnsError::Timeout)??;
+            if src != SocketAddr::from(server) {
+                debug!("dropping response from unexpected source {}", src);
+                continue;
+            }
+
+            match server::parse_response(&rev_data[..len], send_packet) {
+                Ok(rev_packet) => return Ok((rev_packet, len)),
+                Err(DnsError::UnexpectedResponse) => {
+                    debug!("dropping response that doesn't match the query")
+                }
+                Err(e) => debug!("dropping malformed response: {}", e),
+            }
+        }
+    }
+
+    /// Sends `send_packet` over TCP, giving back the response and its size
+    /// in bytes.
+    pub async fn exchange_tcp(
+        send_packet: &Packet,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<(Packet, usize), DnsError> {
+        let send_data = server::encode(send_packet, MAX_TCP_SIZE)?;
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(server).await?;
+            write_tcp_message(&mut stream, &send_data).await?;
+            read_tcp_message(&mut stream).await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")
+            })
+        };
+        let rev_data = time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| DnsError::Timeout)??;
+        let rev_packet = server::parse_response(&rev_data, send_packet)?;
+
+        Ok((rev_packet, rev_data.len()))
+    }
+
+    /// Resolves `qname` like `Server::recursive_lookup`, CNAMEs and all.
+    ///
+    /// Concurrent calls asking the same question share one lookup.
+    pub async fn recursive_lookup(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> Result<Packet, DnsError> {
+        let key = (qname.clone(), qtype);
+        let sender = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(receiver) => Err(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    inflight.insert(key.clone(), receiver);
+                    Ok(sender)
+                }
+            }
+        };
+
+        let sender = match sender {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                return match receiver.wait_for(|e| e.is_some()).await {
+                    Ok(result) => result.clone().unwrap(),
+                    // the lookup we were waiting on was dropped half way
+                    Err(_) => Err(DnsError::Timeout),
+                };
+            }
+        };
+        let _guard = InflightGuard { server: self, key };
+
+        let result = self.resolve(qname, qtype, Trace::off()).await.0;
+        sender.send_replace(Some(result.clone()));
+
+        result
+    }
+
+    /// Resolves `qname` like `Server::trace_lookup`, giving each hop it took
+    /// along with the result.
+    pub async fn trace_lookup(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> (Result<Packet, DnsError>, Vec<Hop>) {
+        self.resolve(qname, qtype, Trace::new()).await
+    }
+
+    /// Waits for a recursion permit, then resolves `qname`, all within the
+    /// lookup deadline.
+    async fn resolve(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+        trace: Trace,
+    ) -> (Result<Packet, DnsError>, Vec<Hop>) {
+        let lookup_deadline = self.server.lookup_deadline;
+        let deadline = Instant::now() + lookup_deadline;
+        match time::timeout(lookup_deadline, self.recursions.acquire()).await {
+            Ok(Ok(_permit)) => {
+                self.run(Resolution::new(qname, qtype, deadline, trace))
+                    .await
+            }
+            _ => (Err(DnsError::Timeout), Vec::new()),
+        }
+    }
+
+    /// Sends the queries `resolution` asks for until it's done, like
+    /// `Server::run`.
+    async fn run(&self, mut resolution: Resolution) -> (Result<Packet, DnsError>, Vec<Hop>) {
+        loop {
+            match resolution.poll(&self.server) {
+                Action::Send {
+                    send_packet,
+                    server,
+                    tcp,
+                    timeout,
+                } => {
+                    let response = if tcp {
+                        AsyncServer::exchange_tcp(&send_packet, server, timeout).await
+                    } else {
+                        AsyncServer::exchange(&send_packet, server, timeout).await
+                    };
+                    resolution.received(response);
+                }
+                Action::Done(result) => return (result, resolution.into_hops()),
+            }
+        }
+    }
+
+    async fn respond(&self, req_packet: Packet) -> Packet {
+        let lookup = match Server::question_to_resolve(&req_packet) {
+            Some(question) => Some(match self.server.zone_for(&question.name) {
+                Some(zone) => Ok(zone.lookup(&question.name, question.qtype)),
+                None => self.recursive_lookup(&question.name, question.qtype).await,
+            }),
+            None => None,
+        };
+        self.server.build_response(req_packet, lookup)
+    }
+
+    /// Answers the query in `req_data`, received on `socket` from `src`.
+    pub async fn handle_datagram(
+        &self,
+        socket: &UdpSocket,
+        req_data: &[u8],
+        src: SocketAddr,
+    ) -> Result<(), DnsError> {
+        let req_packet = match server::parse_request(req_data) {
+            Request::Query(packet) => packet,
+            Request::Malformed(e, res_packet) => {
+                if let Some(res_packet) = res_packet {
+                    socket
+                        .send_to(&server::encode(&res_packet, MAX_UDP_SIZE)?, src)
+                        .await?;
+                }
+                return Err(e);
+            }
+        };
+
+        let res_size = server::udp_response_size(&req_packet);
+        let res_packet = self.respond(req_packet).await;
+        socket
+            .send_to(&server::encode(&res_packet, res_size)?, src)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Answers queries on `socket` forever, each in a task of its own.
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<(), DnsError> {
+        let socket = Arc::new(socket);
+        loop {
+            let mut req_data = [0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut req_data).await?;
+
+            let server = self.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                if let Err(msg) = server.handle_datagram(&socket, &req_data[..len], src).await {
+                    warn!("handle err: {:?}", msg);
+                }
+            });
+        }
+    }
+
+    /// Accepts connections on `listener` forever, each in a task of its own,
+    /// up to `MAX_TCP_CONNECTIONS` at once.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<(), DnsError> {
+        let connections = Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS));
+        loop {
+            let (stream, src) = listener.accept().await?;
+            let permit = match connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!("too many tcp connections, dropped one from {}", src);
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(msg) = server.handle_tcp_connection(stream).await {
+                    warn!("handle tcp err: {:?}", msg);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Answers length-prefixed queries on `stream` until the client closes
+    /// the connection or stays idle for longer than `TCP_IDLE_TIMEOUT`.
+    pub async fn handle_tcp_connection(&self, mut stream: TcpStream) -> Result<(), DnsError> {
+        loop {
+            let req_data =
+                match time::timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream)).await {
+                    Ok(Ok(Some(data))) => data,
+                    Ok(Ok(None)) | Err(_) => return Ok(()),
+                    Ok(Err(e)) => return Err(e.into()),
+                };
+
+            let res_packet = match server::parse_request(&req_data) {
+                Request::Query(req_packet) => self.respond(req_packet).await,
+                Request::Malformed(_, Some(res_packet)) => res_packet,
+                Request::Malformed(e, None) => return Err(e),
+            };
+
+            write_tcp_message(&mut stream, &server::encode(&res_packet, MAX_TCP_SIZE)?).await?;
+        }
+    }
+}
+
+/// Reads one message framed by a two-byte length prefix, or `None` if the
+/// peer closed the connection before starting a new message.
+async fn read_tcp_message<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 2];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut data = server::tcp_message_buf(len_buf);
+    stream.read_exact(&mut data).await?;
+
+    Ok(Some(data))
+}
+
+async fn write_tcp_message<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&server::frame_tcp_message(data)).await
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::dns::header::ResponseCode;
+    use crate::dns::packet::BytePacketBuffer;
+    use crate::dns::record::Record;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn should_lookup_over_udp() {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut req_data = [0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut req_data).await.unwrap();
+            let req_packet =
+                Packet::from_buf(&mut BytePacketBuffer::from_bytes(&req_data[..len])).unwrap();
+
+            let mut res_packet = Packet::new();
+            res_packet.header.id = req_packet.header.id;
+            res_packet.header.query_response = true;
+            res_packet.questions = req_packet.questions;
+            res_packet.answers.push(Record::A {
+                domain: "google.com".parse().unwrap(),
+                ip: Ipv4Addr::new(142, 250, 4, 100),
+                ttl: 300,
+            });
+            let res_data = server::encode(&res_packet, MAX_UDP_SIZE).unwrap();
+            // a malformed response first, to be dropped
+            socket.send_to(&res_data[..5], src).await.unwrap();
+            socket.send_to(&res_data, src).await.unwrap();
+        });
+
+        let res_packet = AsyncServer::lookup(
+            &"google.com".parse().unwrap(),
+            QueryType::A,
+            (addr.ip(), addr.port()),
+            server::DEFAULT_QUERY_TIMEOUT,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            res_packet.random_answer(),
+            Some(IpAddr::V4(Ipv4Addr::new(142, 250, 4, 100)))
+        );
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_answer_tcp_queries() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(AsyncServer::new(Server::new()));
+        tokio::spawn(server.serve_tcp(listener));
+
+        // queries without a question are answered locally with SERVFAIL
+        let mut req_packet = Packet::new();
+        req_packet.header.id = 7;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_tcp_message(
+            &mut stream,
+            &server::encode(&req_packet, MAX_UDP_SIZE).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let data = read_tcp_message(&mut stream).await.unwrap().unwrap();
+        let res_packet = Packet::from_buf(&mut BytePacketBuffer::from_bytes(&data)).unwrap();
+        assert_eq!(res_packet.header.id, 7);
+        assert_eq!(res_packet.header.response_code, ResponseCode::SERVFAIL);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::dns::header::ResponseCode;
+use crate::dns::name::Name;
+use crate::dns::record::{QueryType, Record};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The only class we speak.
+pub const CLASS_IN: u16 = 1;
+
+pub const DEFAULT_CACHE_SIZE: usize = 10000;
+
+/// The type is `None` for an NXDOMAIN, which covers every type at a name.
+type Key = (Name, Option<QueryType>, u16);
+
+enum Data {
+    Records(Vec<Record>),
+    /// NXDOMAIN or NODATA, and the SOA that came with it.
+    Negative(Record),
+}
+
+struct Entry {
+    data: Data,
+    expires: Instant,
+    last_used: u64,
+}
+
+/// RRsets we've been told about, kept until their TTL runs out, along with
+/// the names and types we've been told don't exist (RFC 2308).
+///
+/// Holds at most `max_entries` RRsets and evicts the least recently used
+/// one when full. Every method takes the current time so the cache itself
+/// never looks at the clock.
+pub struct Cache {
+    entries: HashMap<Key, Entry>,
+    // last_used -> key, oldest first
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    max_entries: usize,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            max_entries,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Caches `records`, grouped into RRsets by owner and type. A new RRset
+    /// replaces whatever was cached for the same owner and type. OPT and
+    /// unknown records are ignored.
+    pub fn insert(&mut self, records: &[Record], now: Instant) {
+        let mut rrsets: HashMap<Key, Vec<Record>> = HashMap::new();
+        for rec in records.iter() {
+            if let Record::OPT(_) | Record::UNKNOWN { .. } = rec {
+                continue;
+            }
+            if let Some(domain) = rec.domain() {
+                let key = (domain.clone(), Some(rec.qtype()), CLASS_IN);
+                let rrset = rrsets.entry(key).or_default();
+                if !rrset.contains(rec) {
+                    rrset.push(rec.clone());
+                }
+            }
+        }
+
+        for (key, records) in rrsets {
+            let ttl = records.iter().map(|e| e.ttl()).min().unwrap_or(0);
+            if ttl == 0 {
+                continue;
+            }
+            let expires = now + Duration::from_secs(ttl as u64);
+            self.insert_entry(key, Data::Records(records), expires);
+        }
+    }
+
+    /// Caches that `name` doesn't exist, if `qtype` is `None`, or that it
+    /// has no records of `qtype`. It's remembered for the SOA's TTL or its
+    /// minimum field, whichever is lower.
+    pub fn insert_negative(
+        &mut self,
+        name: &Name,
+        qtype: Option<QueryType>,
+        soa: &Record,
+        now: Instant,
+    ) {
+        let ttl = match soa {
+            Record::SOA { minimum, ttl, .. } => *minimum.min(ttl),
+            _ => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let key = (name.clone(), qtype, CLASS_IN);
+        let expires = now + Duration::from_secs(ttl as u64);
+        self.insert_entry(key, Data::Negative(soa.clone()), expires);
+    }
+
+    fn insert_entry(&mut self, key: Key, data: Data, expires: Instant) {
+        self.tick += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.lru.remove(&old.last_used);
+        }
+
+        while self.entries.len() >= self.max_entries {
+            match self.lru.iter().next().map(|(tick, _)| *tick) {
+                Some(tick) => {
+                    let key = self.lru.remove(&tick).unwrap();
+                    self.entries.remove(&key);
+                }
+                None => return,
+            }
+        }
+
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                data,
+                expires,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    /// The cached RRset for `name` and `qtype`, with TTLs counted down to
+    /// what's left of them.
+    pub fn lookup(&mut self, name: &Name, qtype: QueryType, now: Instant) -> Option<Vec<Record>> {
+        let (data, remaining) = self.get((name.clone(), Some(qtype), CLASS_IN), now)?;
+        match data {
+            Data::Records(records) => Some(
+                records
+                    .iter()
+                    .cloned()
+                    .map(|mut e| {
+                        e.set_ttl(remaining);
+                        e
+                    })
+                    .collect(),
+            ),
+            Data::Negative(_) => None,
+        }
+    }
+
+    /// Whether we know there's no `qtype` at `name`: NXDOMAIN if the name
+    /// doesn't exist at all, NOERROR if it just has no records of the type.
+    /// Comes with the SOA to put in the authority section, TTL counted down.
+    pub fn lookup_negative(
+        &mut self,
+        name: &Name,
+        qtype: QueryType,
+        now: Instant,
+    ) -> Option<(ResponseCode, Record)> {
+        let keys = [
+            (None, ResponseCode::NXDOMAIN),
+            (Some(qtype), ResponseCode::NOERROR),
+        ];
+        for (key_type, rcode) in keys.iter() {
+            let key = (name.clone(), *key_type, CLASS_IN);
+            if let Some((Data::Negative(soa), remaining)) = self.get(key, now) {
+                let mut soa = soa.clone();
+                soa.set_ttl(remaining);
+                return Some((*rcode, soa));
+            }
+        }
+        None
+    }
+
+    /// The entry under `key` and its remaining TTL, dropping it if it has
+    /// expired.
+    fn get(&mut self, key: Key, now: Instant) -> Option<(&Data, u32)> {
+        if self.entries.get(&key)?.expires <= now {
+            let entry = self.entries.remove(&key).unwrap();
+            self.lru.remove(&entry.last_used);
+            return None;
+        }
+
+        let entry = self.entries.get_mut(&key).unwrap();
+        self.tick += 1;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.tick, key);
+        entry.last_used = self.tick;
+
+        // round up, an entry that hasn't expired never goes out with TTL 0
+        let left = entry.expires - now;
+        let remaining = (left.as_secs() + (left.subsec_nanos() > 0) as u64) as u32;
+
+        Some((&entry.data, remaining))
+    }
+
+    /// The closest enclosing zone of `name` that we have nameservers with
+    /// known addresses for, along with those addresses.
+    pub fn delegation(&mut self, name: &Name, now: Instant) -> Option<(Name, Vec<IpAddr>)> {
+        for zone in name.ancestors() {
+            let ns = match self.lookup(&zone, QueryType::NS, now) {
+                Some(ns) => ns,
+                None => continue,
+            };
+
+            let mut addrs = Vec::new();
+            for rec in ns.iter() {
+                let host = match rec {
+                    Record::NS { host, .. } => host,
+                    _ => continue,
+                };
+                for qtype in [QueryType::A, QueryType::AAAA].iter() {
+                    for addr in self.lookup(host, *qtype, now).unwrap_or_default() {
+                        match addr {
+                            Record::A { ip, .. } => addrs.push(IpAddr::V4(ip)),
+                            Record::AAAA { ip, .. } => addrs.push(IpAddr::V6(ip)),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            if !addrs.is_empty() {
+                return Some((zone, addrs));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn a(domain: &str, last: u8, ttl: u32) -> Record {
+        Record::A {
+            domain: domain.parse().unwrap(),
+            ip: Ipv4Addr::new(10, 0, 0, last),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn should_count_down_ttl() {
+        let mut cache = Cache::new(DEFAULT_CACHE_SIZE);
+        let now = Instant::now();
+        cache.insert(&[a("google.com", 1, 60), a("google.com", 2, 60)], now);
+
+        let name = "GOOGLE.com".parse().unwrap();
+        let records = cache
+            .lookup(&name, QueryType::A, now + Duration::from_secs(20))
+            .unwrap();
+
+        assert_eq!(records, [a("google.com", 1, 40), a("google.com", 2, 40)]);
+        assert_eq!(cache.lookup(&name, QueryType::AAAA, now), None);
+    }
+
+    #[test]
+    fn should_expire() {
+        let mut cache = Cache::new(DEFAULT_CACHE_SIZE);
+        let now = Instant::now();
+        cache.insert(&[a("google.com", 1, 60)], now);
+
+        let name = "google.com".parse().unwrap();
+        assert!(cache
+            .lookup(&name, QueryType::A, now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn should_evict_least_recently_used() {
+        let mut cache = Cache::new(2);
+        let now = Instant::now();
+        cache.insert(&[a("a.com", 1, 60)], now);
+        cache.insert(&[a("b.com", 2, 60)], now);
+        cache.lookup(&"a.com".parse().unwrap(), QueryType::A, now);
+        cache.insert(&[a("c.com", 3, 60)], now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache
+            .lookup(&"a.com".parse().unwrap(), QueryType::A, now)
+            .is_some());
+        assert!(cache
+            .lookup(&"b.com".parse().unwrap(), QueryType::A, now)
+            .is_none());
+        assert!(cache
+            .lookup(&"c.com".parse().unwrap(), QueryType::A, now)
+            .is_some());
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> Record {
+        Record::SOA {
+            domain: "google.com".parse().unwrap(),
+            mname: "ns1.google.com".parse().unwrap(),
+            rname: "dns-admin.google.com".parse().unwrap(),
+            serial: 1,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn should_cache_nxdomain_for_every_type() {
+        let mut cache = Cache::new(DEFAULT_CACHE_SIZE);
+        let now = Instant::now();
+        let name = "nope.google.com".parse().unwrap();
+        cache.insert_negative(&name, None, &soa(60, 30), now);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            cache.lookup_negative(&name, QueryType::MX, later),
+            Some((ResponseCode::NXDOMAIN, soa(20, 30)))
+        );
+        assert_eq!(cache.lookup(&name, QueryType::A, later), None);
+        assert_eq!(
+            cache.lookup_negative(&name, QueryType::A, now + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn should_cache_nodata_per_type() {
+        let mut cache = Cache::new(DEFAULT_CACHE_SIZE);
+        let now = Instant::now();
+        let name = "google.com".parse().unwrap();
+        cache.insert_negative(&name, Some(QueryType::AAAA), &soa(60, 300), now);
+
+        assert_eq!(
+            cache.lookup_negative(&name, QueryType::AAAA, now),
+            Some((ResponseCode::NOERROR, soa(60, 300)))
+        );
+        assert_eq!(cache.lookup_negative(&name, QueryType::A, now), None);
+
+        // real records replace what we knew about their absence
+        cache.insert(&[a("google.com", 1, 60)], now);
+        cache.insert(
+            &[Record::AAAA {
+                domain: "google.com".parse().unwrap(),
+                ip: "2404:6800:4003:c00::8a".parse().unwrap(),
+                ttl: 60,
+            }],
+            now,
+        );
+        assert_eq!(cache.lookup_negative(&name, QueryType::AAAA, now), None);
+    }
+
+    #[test]
+    fn should_find_closest_delegation() {
+        let mut cache = Cache::new(DEFAULT_CACHE_SIZE);
+        let now = Instant::now();
+        cache.insert(
+            &[
+                Record::NS {
+                    domain: "com".parse().unwrap(),
+                    host: "a.gtld-servers.net".parse().unwrap(),
+                    ttl: 3600,
+                },
+                a("a.gtld-servers.net", 30, 3600),
+                // no address for this one, so google.com is no use yet
+                Record::NS {
+                    domain: "google.com".parse().unwrap(),
+                    host: "ns1.google.com".parse().unwrap(),
+                    ttl: 3600,
+                },
+            ],
+            now,
+        );
+
+        let name = "www.google.com".parse().unwrap();
+        assert_eq!(
+            cache.delegation(&name, now),
+            Some((
+                "com".parse().unwrap(),
+                vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 30))]
+            ))
+        );
+
+        cache.insert(&[a("ns1.google.com", 10, 3600)], now);
+        assert_eq!(
+            cache.delegation(&name, now),
+            Some((
+                "google.com".parse().unwrap(),
+                vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 10))]
+            ))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong reading, writing or exchanging messages.
+///
+/// Parse errors carry the byte offset in the message where they were hit.
+#[derive(Debug)]
+pub enum DnsError {
+    /// A write went past the buffer's max size.
+    BufferOverrun {
+        offset: usize,
+    },
+    /// A label with a reserved length prefix or a name longer than 255 bytes.
+    MalformedLabel {
+        offset: usize,
+    },
+    /// A compression pointer that never reaches the end of a name.
+    CompressionLoop {
+        offset: usize,
+    },
+    /// Record data that can't be read or written for this type.
+    UnsupportedType {
+        offset: usize,
+        qtype: u16,
+    },
+    /// The message ended before a field did, or a record's fields didn't
+    /// add up to its RDLENGTH.
+    TruncatedMessage {
+        offset: usize,
+    },
+    /// An upstream response whose ID or question doesn't match our query.
+    UnexpectedResponse,
+    /// A name in presentation form that doesn't make a valid `Name`.
+    InvalidName(String),
+    Io(io::Error),
+    Timeout,
+}
+
+impl DnsError {
+    /// Whether the error means the peer sent us a message we can't parse,
+    /// as opposed to something failing on our side.
+    pub fn is_format_error(&self) -> bool {
+        matches!(
+            self,
+            DnsError::MalformedLabel { .. }
+                | DnsError::CompressionLoop { .. }
+                | DnsError::TruncatedMessage { .. }
+        )
+    }
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsError::BufferOverrun { offset } => write!(f, "buffer full at offset {}", offset),
+            DnsError::MalformedLabel { offset } => {
+                write!(f, "malformed label at offset {}", offset)
+            }
+            DnsError::CompressionLoop { offset } => {
+                write!(f, "compression loop at offset {}", offset)
+            }
+            DnsError::UnsupportedType { offset, qtype } => {
+                write!(f, "unsupported type {} at offset {}", qtype, offset)
+            }
+            DnsError::TruncatedMessage { offset } => {
+                write!(f, "message truncated at offset {}", offset)
+            }
+            DnsError::UnexpectedResponse => write!(f, "response doesn't match the query"),
+            DnsError::InvalidName(msg) => write!(f, "invalid name: {}", msg),
+            DnsError::Io(e) => write!(f, "i/o error: {}", e),
+            DnsError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl Error for DnsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DnsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // a socket read timeout shows up as either, depending on platform
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DnsError::Timeout,
+            _ => DnsError::Io(e),
+        }
+    }
+}
+
+impl Clone for DnsError {
+    /// `io::Error` can't be cloned, so the copy of an `Io` keeps only its
+    /// kind and message.
+    fn clone(&self) -> Self {
+        match self {
+            DnsError::BufferOverrun { offset } => DnsError::BufferOverrun { offset: *offset },
+            DnsError::MalformedLabel { offset } => DnsError::MalformedLabel { offset: *offset },
+            DnsError::CompressionLoop { offset } => DnsError::CompressionLoop { offset: *offset },
+            DnsError::UnsupportedType { offset, qtype } => DnsError::UnsupportedType {
+                offset: *offset,
+                qtype: *qtype,
+            },
+            DnsError::TruncatedMessage { offset } => DnsError::TruncatedMessage { offset: *offset },
+            DnsError::UnexpectedResponse => DnsError::UnexpectedResponse,
+            DnsError::InvalidName(msg) => DnsError::InvalidName(msg.clone()),
+            DnsError::Io(e) => DnsError::Io(io::Error::new(e.kind(), e.to_string())),
+            DnsError::Timeout => DnsError::Timeout,
+        }
+    }
+}
+
+impl PartialEq for DnsError {
+    fn eq(&self, other: &DnsError) -> bool {
+        match (self, other) {
+            (DnsError::Io(a), DnsError::Io(b)) => a.kind() == b.kind(),
+            (DnsError::BufferOverrun { offset: a }, DnsError::BufferOverrun { offset: b }) => {
+                a == b
+            }
+            (DnsError::MalformedLabel { offset: a }, DnsError::MalformedLabel { offset: b }) => {
+                a == b
+            }
+            (DnsError::CompressionLoop { offset: a }, DnsError::CompressionLoop { offset: b }) => {
+                a == b
+            }
+            (
+                DnsError::UnsupportedType {
+                    offset: a,
+                    qtype: x,
+                },
+                DnsError::UnsupportedType {
+                    offset: b,
+                    qtype: y,
+                },
+            ) => a == b && x == y,
+            (
+                DnsError::TruncatedMessage { offset: a },
+                DnsError::TruncatedMessage { offset: b },
+            ) => a == b,
+            (DnsError::InvalidName(a), DnsError::InvalidName(b)) => a == b,
+            (DnsError::UnexpectedResponse, DnsError::UnexpectedResponse) => true,
+            (DnsError::Timeout, DnsError::Timeout) => true,
+            _ => false,
+        }
+    }
+}
@@ -1,6 +1,8 @@
+use crate::dns::error::DnsError;
 use crate::dns::packet::BytePacketBuffer;
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Header {
     pub id: u16,
     pub query_response: bool,
@@ -40,6 +42,12 @@ impl ResponseCode {
     }
 }
 
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
 impl Header {
     pub fn new() -> Self {
         Header {
@@ -59,7 +67,7 @@ impl Header {
         }
     }
 
-    pub fn read(&mut self, packet_buffer: &mut BytePacketBuffer) -> Result<(), String> {
+    pub fn read(&mut self, packet_buffer: &mut BytePacketBuffer) -> Result<(), DnsError> {
         packet_buffer.seek(0)?;
         self.id = packet_buffer.read_u16()?;
         let b1 = packet_buffer.read()?;
@@ -81,13 +89,13 @@ impl Header {
         Ok(())
     }
 
-    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), String> {
+    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
         packet_buf.write_u16(self.id)?;
         packet_buf.write_u8(
-            ((self.query_response as u8) << 7) as u8
-                | (self.opcode << 3) as u8
-                | ((self.authoritative_answer as u8) << 2) as u8
-                | ((self.truncated_message as u8) << 1) as u8
+            ((self.query_response as u8) << 7)
+                | (self.opcode << 3)
+                | ((self.authoritative_answer as u8) << 2)
+                | ((self.truncated_message as u8) << 1)
                 | self.recursion_desired as u8,
         )?;
 
@@ -106,6 +114,48 @@ impl Header {
     }
 }
 
+impl fmt::Display for Header {
+    /// The two header lines of dig's output.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opcode = match self.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            n => n.to_string(),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            opcode, self.response_code, self.id
+        )?;
+
+        let flags = [
+            (self.query_response, " qr"),
+            (self.authoritative_answer, " aa"),
+            (self.truncated_message, " tc"),
+            (self.recursion_desired, " rd"),
+            (self.recursion_available, " ra"),
+        ];
+        f.write_str(";; flags:")?;
+        for (_, flag) in flags.iter().filter(|e| e.0) {
+            f.write_str(flag)?;
+        }
+        write!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.question_count, self.answer_count, self.authority_count, self.additional_count
+        )
+    }
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -126,12 +176,12 @@ mod test {
         let result = header.read(&mut packet_buffer);
 
         assert_eq!(header.id, 35419);
-        assert_eq!(header.query_response, true);
+        assert!(header.query_response);
         assert_eq!(header.opcode, 0);
-        assert_eq!(header.authoritative_answer, true);
-        assert_eq!(header.truncated_message, false);
-        assert_eq!(header.recursion_desired, true);
-        assert_eq!(header.recursion_available, true);
+        assert!(header.authoritative_answer);
+        assert!(!header.truncated_message);
+        assert!(header.recursion_desired);
+        assert!(header.recursion_available);
         assert_eq!(header.reserved, 0);
         assert_eq!(header.response_code, ResponseCode::NOERROR);
         assert_eq!(header.question_count, 1);
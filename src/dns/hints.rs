@@ -0,0 +1,153 @@
+use crate::dns::name::Name;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+/// What can go wrong reading a root hints file.
+#[derive(Debug)]
+pub enum HintsError {
+    Io(io::Error),
+    /// A line we can't make sense of, counting from 1.
+    Syntax {
+        line: usize,
+        msg: String,
+    },
+    /// The file names no root servers we have an address for.
+    NoServers,
+}
+
+impl fmt::Display for HintsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HintsError::Io(e) => write!(f, "i/o error: {}", e),
+            HintsError::Syntax { line, msg } => write!(f, "line {}: {}", line, msg),
+            HintsError::NoServers => write!(f, "no root servers with addresses"),
+        }
+    }
+}
+
+impl std::error::Error for HintsError {}
+
+impl From<io::Error> for HintsError {
+    fn from(e: io::Error) -> HintsError {
+        HintsError::Io(e)
+    }
+}
+
+/// Reads the root hints file at `path`, see `parse`.
+pub fn load(path: &str) -> Result<Vec<IpAddr>, HintsError> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// The root servers' addresses from a hints file in the format of IANA's
+/// `named.root`: NS records for `.`, and A and AAAA records for the hosts
+/// they name, with `;` comments. Addresses come in the order their hosts
+/// are named, IPv4 first for each.
+pub fn parse(text: &str) -> Result<Vec<IpAddr>, HintsError> {
+    let mut hosts: Vec<Name> = Vec::new();
+    let mut addrs: HashMap<Name, Vec<IpAddr>> = HashMap::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let syntax = |msg: String| HintsError::Syntax { line: i + 1, msg };
+
+        let line = line.split(';').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let owner = match fields.next() {
+            Some(owner) => owner,
+            None => continue,
+        };
+        let owner: Name = owner
+            .parse()
+            .map_err(|_| syntax(format!("bad name {}", owner)))?;
+
+        // the TTL and class are optional and don't matter to us
+        let mut fields = fields.skip_while(|e| e.parse::<u32>().is_ok() || e == &"IN");
+        let (rtype, rdata) = match (fields.next(), fields.next()) {
+            (Some(rtype), Some(rdata)) => (rtype, rdata),
+            _ => return Err(syntax("expected a type and its data".to_string())),
+        };
+
+        match rtype.to_ascii_uppercase().as_str() {
+            "NS" if owner.is_root() => {
+                let host: Name = rdata
+                    .parse()
+                    .map_err(|_| syntax(format!("bad name {}", rdata)))?;
+                hosts.push(host);
+            }
+            "A" | "AAAA" => {
+                let ip: IpAddr = rdata
+                    .parse()
+                    .map_err(|_| syntax(format!("bad address {}", rdata)))?;
+                if ip.is_ipv4() != (rtype.eq_ignore_ascii_case("A")) {
+                    return Err(syntax(format!("{} isn't an {} address", rdata, rtype)));
+                }
+                addrs.entry(owner).or_default().push(ip);
+            }
+            _ => return Err(syntax(format!("unexpected {} record", rtype))),
+        }
+    }
+
+    let mut hints = Vec::new();
+    for host in hosts.iter() {
+        let mut host_addrs = addrs.remove(host).unwrap_or_default();
+        host_addrs.sort_by_key(|e| e.is_ipv6());
+        hints.extend(host_addrs);
+    }
+    if hints.is_empty() {
+        return Err(HintsError::NoServers);
+    }
+
+    Ok(hints)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn should_parse_named_root() {
+        let text = "\
+;       This file holds the information on root name servers needed to
+;       initialize cache of Internet domain name servers
+;
+; FORMERLY NS.INTERNIC.NET
+;
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+;
+; FORMERLY NS1.ISI.EDU
+;
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2
+B.ROOT-SERVERS.NET.      3600000      AAAA  2801:1b8:10::b
+; END OF FILE
+";
+
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 2, 0x30)),
+                IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+                IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_bad_hints() {
+        match parse(".  3600000  NS  A.ROOT-SERVERS.NET.\nA.ROOT-SERVERS.NET.  A  198.41.0\n") {
+            Err(HintsError::Syntax { line, .. }) => assert_eq!(line, 2),
+            e => panic!("expected a syntax error, got {:?}", e),
+        }
+        assert!(matches!(
+            parse("A.ROOT-SERVERS.NET.  3600000  A  198.41.0.4\n"),
+            Err(HintsError::NoServers)
+        ));
+    }
+}
@@ -1,5 +1,15 @@
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod cache;
+pub mod error;
 pub mod header;
+pub mod hints;
+pub mod name;
 pub mod packet;
+pub mod pool;
 pub mod question;
 pub mod record;
+pub(crate) mod resolver;
 pub mod server;
+pub mod trace;
+pub mod zone;
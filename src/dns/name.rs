@@ -0,0 +1,369 @@
+use crate::dns::error::DnsError;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+pub const MAX_LABEL_LEN: usize = 63;
+/// Limit on the wire form, including length bytes and the root label.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// A fully qualified domain name.
+///
+/// Labels are kept as raw bytes without the root label, so a `Name` can
+/// always be written to the wire: every label is 1..=63 bytes and the whole
+/// name is at most 255 bytes. Comparison and hashing ignore ASCII case.
+#[derive(Clone, Default)]
+pub struct Name {
+    labels: Vec<Vec<u8>>,
+}
+
+impl Name {
+    pub fn root() -> Name {
+        Name { labels: Vec::new() }
+    }
+
+    /// Builds a name from its labels, most specific first.
+    pub fn from_labels<I, L>(labels: I) -> Result<Name, DnsError>
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<Vec<u8>>,
+    {
+        let mut name = Name::root();
+        let mut len = 1;
+        for label in labels {
+            let label = label.into();
+            if label.is_empty() {
+                return Err(DnsError::InvalidName("empty label".to_string()));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(DnsError::InvalidName(format!(
+                    "label more than {} bytes",
+                    MAX_LABEL_LEN
+                )));
+            }
+
+            len += label.len() + 1;
+            if len > MAX_NAME_LEN {
+                return Err(DnsError::InvalidName(format!(
+                    "name more than {} bytes",
+                    MAX_NAME_LEN
+                )));
+            }
+            name.labels.push(label);
+        }
+
+        Ok(name)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Iterates over the labels, most specific first, without the root.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.labels.iter().map(|e| e.as_slice())
+    }
+
+    /// Length of the uncompressed wire form.
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|e| e.len() + 1).sum::<usize>() + 1
+    }
+
+    /// The name with its first label removed, `None` for the root.
+    pub fn parent(&self) -> Option<Name> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(Name {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// The name itself followed by each ancestor up to and including the
+    /// root, i.e. every possible zone cut from the closest to the furthest.
+    pub fn ancestors(&self) -> impl Iterator<Item = Name> + '_ {
+        (0..=self.labels.len()).map(move |i| Name {
+            labels: self.labels[i..].to_vec(),
+        })
+    }
+
+    /// Prepends `label`, e.g. `www` to `example.com`.
+    pub fn child<L: Into<Vec<u8>>>(&self, label: L) -> Result<Name, DnsError> {
+        let labels = std::iter::once(label.into()).chain(self.labels.iter().cloned());
+        Name::from_labels(labels)
+    }
+
+    /// Whether `self` is `other` or lies below it. Every name is a
+    /// subdomain of the root.
+    pub fn is_subdomain_of(&self, other: &Name) -> bool {
+        if other.labels.len() > self.labels.len() {
+            return false;
+        }
+
+        self.labels
+            .iter()
+            .rev()
+            .zip(other.labels.iter().rev())
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl FromStr for Name {
+    type Err = DnsError;
+
+    /// Parses presentation form: `\.` escapes a dot inside a label and
+    /// `\DDD` gives a byte in decimal. The trailing dot is optional and
+    /// both `""` and `"."` are the root.
+    fn from_str(s: &str) -> Result<Name, DnsError> {
+        if s == "." {
+            return Ok(Name::root());
+        }
+
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut chars = s.bytes();
+        let mut pending = false;
+
+        while let Some(c) = chars.next() {
+            match c {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(DnsError::InvalidName(format!("empty label in {:?}", s)));
+                    }
+                    labels.push(std::mem::take(&mut label));
+                    pending = false;
+                    continue;
+                }
+                b'\\' => {
+                    let c = chars.next().ok_or_else(|| {
+                        DnsError::InvalidName(format!("dangling escape in {:?}", s))
+                    })?;
+                    if c.is_ascii_digit() {
+                        let digits = [c, chars.next().unwrap_or(0), chars.next().unwrap_or(0)];
+                        if !digits.iter().all(|e| e.is_ascii_digit()) {
+                            return Err(DnsError::InvalidName(format!(
+                                "bad \\DDD escape in {:?}",
+                                s
+                            )));
+                        }
+                        let value = digits
+                            .iter()
+                            .fold(0u32, |acc, e| acc * 10 + (e - b'0') as u32);
+                        if value > 255 {
+                            return Err(DnsError::InvalidName(format!(
+                                "bad \\DDD escape in {:?}",
+                                s
+                            )));
+                        }
+                        label.push(value as u8);
+                    } else {
+                        label.push(c);
+                    }
+                }
+                _ => label.push(c),
+            }
+            pending = true;
+        }
+
+        if pending {
+            labels.push(label);
+        }
+
+        Name::from_labels(labels)
+    }
+}
+
+impl fmt::Display for Name {
+    /// Writes presentation form with a trailing dot, escaping anything
+    /// that would not read back as the same label.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+
+        for label in self.labels.iter() {
+            for &b in label.iter() {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    0x21..=0x7e => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+            f.write_str(".")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.labels.len() == other.labels.len() && self.is_subdomain_of(other)
+    }
+}
+
+impl Eq for Name {}
+
+impl PartialEq<str> for Name {
+    fn eq(&self, other: &str) -> bool {
+        other.parse::<Name>().is_ok_and(|e| *self == e)
+    }
+}
+
+impl PartialEq<&str> for Name {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.labels.len().hash(state);
+        for label in self.labels.iter() {
+            label.len().hash(state);
+            for b in label.iter() {
+                b.to_ascii_lowercase().hash(state);
+            }
+        }
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    /// Canonical DNS order (RFC 4034 section 6.1): compare labels from the
+    /// root down, case-insensitively.
+    fn cmp(&self, other: &Name) -> Ordering {
+        let lower = |e: &Vec<u8>| e.to_ascii_lowercase();
+        self.labels
+            .iter()
+            .rev()
+            .map(lower)
+            .cmp(other.labels.iter().rev().map(lower))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn should_parse() {
+        let name: Name = "www.Google.com.".parse().unwrap();
+
+        assert_eq!(name.label_count(), 3);
+        assert_eq!(name.labels().next(), Some(&b"www"[..]));
+        assert_eq!(name.to_string(), "www.Google.com.");
+        assert_eq!(name, "WWW.google.COM");
+    }
+
+    #[test]
+    fn should_parse_root() {
+        assert!("".parse::<Name>().unwrap().is_root());
+        assert!(".".parse::<Name>().unwrap().is_root());
+        assert_eq!(Name::root().to_string(), ".");
+    }
+
+    #[test]
+    fn should_parse_escapes() {
+        let name: Name = r"a\.b.c\032d\255.com".parse().unwrap();
+        let labels: Vec<&[u8]> = name.labels().collect();
+
+        assert_eq!(labels, [&b"a.b"[..], &b"c d\xff"[..], &b"com"[..]]);
+        assert_eq!(name.to_string(), r"a\.b.c\032d\255.com.");
+        assert_eq!(name.to_string().parse::<Name>().unwrap(), name);
+    }
+
+    #[test]
+    fn should_reject_invalid() {
+        assert!("a..com".parse::<Name>().is_err());
+        assert!(".com".parse::<Name>().is_err());
+        assert!(r"a\".parse::<Name>().is_err());
+        assert!(r"a\25".parse::<Name>().is_err());
+        assert!(r"a\256".parse::<Name>().is_err());
+        assert!("a".repeat(64).parse::<Name>().is_err());
+        assert!("a".repeat(63).parse::<Name>().is_ok());
+
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(long.parse::<Name>().is_err());
+        assert!(long[2..].parse::<Name>().is_ok());
+    }
+
+    #[test]
+    fn should_compare_case_insensitive() {
+        use std::collections::HashSet;
+
+        let a: Name = "Example.COM".parse().unwrap();
+        let b: Name = "example.com".parse().unwrap();
+        let mut set = HashSet::new();
+        set.insert(a.clone());
+
+        assert_eq!(a, b);
+        assert!(set.contains(&b));
+        assert_eq!(a.to_string(), "Example.COM.");
+    }
+
+    #[test]
+    fn should_walk_up() {
+        let name: Name = "www.example.com".parse().unwrap();
+        let ancestors: Vec<String> = name.ancestors().map(|e| e.to_string()).collect();
+
+        assert_eq!(ancestors, ["www.example.com.", "example.com.", "com.", "."]);
+        assert_eq!(name.parent().unwrap(), "example.com");
+        assert_eq!(Name::root().parent(), None);
+        assert_eq!(
+            name.parent().unwrap().child("ftp").unwrap(),
+            "ftp.example.com"
+        );
+    }
+
+    #[test]
+    fn should_check_subdomain() {
+        let name: Name = "www.example.com".parse().unwrap();
+
+        assert!(name.is_subdomain_of(&"EXAMPLE.com".parse().unwrap()));
+        assert!(name.is_subdomain_of(&name));
+        assert!(name.is_subdomain_of(&Name::root()));
+        assert!(!name.is_subdomain_of(&"ample.com".parse().unwrap()));
+        assert!(!name.is_subdomain_of(&"a.www.example.com".parse().unwrap()));
+    }
+
+    #[test]
+    fn should_order_canonically() {
+        let mut names: Vec<Name> = ["z.example", "example", "a.example", "yljkjljk.a.example"]
+            .iter()
+            .map(|e| e.parse().unwrap())
+            .collect();
+        names.sort();
+        let names: Vec<String> = names.iter().map(|e| e.to_string()).collect();
+
+        assert_eq!(
+            names,
+            [
+                "example.",
+                "a.example.",
+                "yljkjljk.a.example.",
+                "z.example."
+            ]
+        );
+    }
+}
@@ -1,19 +1,54 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::Name;
+use std::collections::HashMap;
+
+/// Largest message a plain DNS client is guaranteed to accept over UDP.
+pub const MAX_UDP_SIZE: usize = 512;
+/// Payload size we advertise and accept for EDNS(0) over UDP.
+pub const MAX_EDNS_SIZE: usize = 4096;
+/// Largest message that fits behind the two-byte TCP length prefix.
+pub const MAX_TCP_SIZE: usize = 65535;
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pos: usize,
+    max_size: usize,
+    // name suffix -> offset where it was written, `None` when compression is off
+    names: Option<HashMap<Name, usize>>,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> Self {
+        BytePacketBuffer::with_max_size(MAX_UDP_SIZE)
+    }
+
+    /// Creates a buffer that grows on write up to `max_size` bytes.
+    pub fn with_max_size(max_size: usize) -> Self {
+        BytePacketBuffer {
+            buf: vec![0; max_size.min(MAX_UDP_SIZE)],
+            pos: 0,
+            max_size,
+            names: None,
+        }
+    }
+
+    /// Wraps a received message, e.g. the filled part of a socket read.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: bytes.to_vec(),
             pos: 0,
+            max_size: MAX_TCP_SIZE,
+            names: None,
         }
     }
 
-    pub fn read(&mut self) -> Result<u8, String> {
-        if self.pos >= 512 {
-            return Err("more than 512".to_string());
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn read(&mut self) -> Result<u8, DnsError> {
+        if self.pos >= self.buf.len() {
+            return Err(DnsError::TruncatedMessage { offset: self.pos });
         }
 
         let res = self.buf[self.pos];
@@ -22,23 +57,31 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
-    pub fn read_u16(&mut self) -> Result<u16, String> {
+    pub fn read_u16(&mut self) -> Result<u16, DnsError> {
         let b1 = (self.read()? as u16) << 8;
         let b2 = self.read()? as u16;
         let res = b1 | b2;
         Ok(res)
     }
 
-    pub fn read_range(&mut self, start: usize, len: usize) -> Result<&[u8], String> {
-        if start + len >= 512 {
-            return Err("more than 512".to_string());
+    pub fn read_u32(&mut self) -> Result<u32, DnsError> {
+        let b1 = (self.read_u16()? as u32) << 16;
+        let b2 = self.read_u16()? as u32;
+        Ok(b1 | b2)
+    }
+
+    pub fn read_range(&mut self, start: usize, len: usize) -> Result<&[u8], DnsError> {
+        if start + len > self.buf.len() {
+            return Err(DnsError::TruncatedMessage {
+                offset: self.buf.len(),
+            });
         }
         Ok(&self.buf[start..start + len])
     }
 
-    pub fn seek(&mut self, pos: usize) -> Result<(), String> {
-        if pos >= 512 {
-            return Err("The pos more than 512".to_string());
+    pub fn seek(&mut self, pos: usize) -> Result<(), DnsError> {
+        if pos > self.max_size {
+            return Err(DnsError::BufferOverrun { offset: pos });
         }
         self.pos = pos;
         Ok(())
@@ -48,16 +91,20 @@ impl BytePacketBuffer {
         self.pos
     }
 
-    pub fn get(&mut self, pos: usize) -> Result<u8, String> {
-        if pos >= 512 {
-            return Err("End of buffer".to_string());
+    pub fn get(&mut self, pos: usize) -> Result<u8, DnsError> {
+        if pos >= self.buf.len() {
+            return Err(DnsError::TruncatedMessage { offset: pos });
         }
         Ok(self.buf[pos])
     }
 
-    pub fn write(&mut self, b: u8) -> Result<(), String> {
-        if self.pos + 1 >= 512 {
-            return Err("End of buffer".to_string());
+    pub fn write(&mut self, b: u8) -> Result<(), DnsError> {
+        if self.pos >= self.max_size {
+            return Err(DnsError::BufferOverrun { offset: self.pos });
+        }
+
+        if self.pos >= self.buf.len() {
+            self.buf.resize(self.pos + 1, 0);
         }
 
         self.buf[self.pos] = b;
@@ -66,14 +113,64 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    pub fn write_u8(&mut self, b: u8) -> Result<(), String> {
+    pub fn write_u8(&mut self, b: u8) -> Result<(), DnsError> {
         self.write(b)
     }
 
-    pub fn write_u16(&mut self, b: u16) -> Result<(), String> {
+    pub fn write_u16(&mut self, b: u16) -> Result<(), DnsError> {
         self.write_u8((b >> 8) as u8)?;
         self.write_u8((b & 0xFF) as u8)
     }
+
+    pub fn write_u32(&mut self, b: u32) -> Result<(), DnsError> {
+        self.write_u16((b >> 16) as u16)?;
+        self.write_u16((b & 0xFFFF) as u16)
+    }
+
+    /// Overwrites two already written bytes, e.g. to fill in an RDLENGTH.
+    pub fn set_u16(&mut self, pos: usize, b: u16) -> Result<(), DnsError> {
+        if pos + 2 > self.buf.len() {
+            return Err(DnsError::BufferOverrun { offset: pos });
+        }
+        self.buf[pos] = (b >> 8) as u8;
+        self.buf[pos + 1] = (b & 0xFF) as u8;
+        Ok(())
+    }
+
+    /// Discards everything written from `pos` on, including names that
+    /// later writes could otherwise point back into.
+    pub fn truncate(&mut self, pos: usize) -> Result<(), DnsError> {
+        self.seek(pos)?;
+        if let Some(names) = self.names.as_mut() {
+            names.retain(|_, offset| *offset < pos);
+        }
+        Ok(())
+    }
+
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.names = if enabled { Some(HashMap::new()) } else { None };
+    }
+
+    /// Offset of an earlier occurrence of `name`, if compression is on.
+    pub fn find_name(&self, name: &Name) -> Option<usize> {
+        self.names.as_ref()?.get(name).copied()
+    }
+
+    /// Remembers that `name` starts at `pos`. Only the first 14 bits of
+    /// offset are addressable by a compression pointer.
+    pub fn save_name(&mut self, name: &Name, pos: usize) {
+        if let Some(names) = self.names.as_mut() {
+            if pos < 0x4000 {
+                names.entry(name.clone()).or_insert(pos);
+            }
+        }
+    }
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        BytePacketBuffer::new()
+    }
 }
 
 #[cfg(test)]
@@ -156,4 +253,45 @@ mod test {
         let _ = packet_buf.seek(0);
         assert_eq!(packet_buf.read().unwrap(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_grow_up_to_max_size() {
+        let mut packet_buf = BytePacketBuffer::with_max_size(MAX_EDNS_SIZE);
+        for _ in 0..1000 {
+            packet_buf.write_u8(0xab).unwrap();
+        }
+
+        assert_eq!(packet_buf.pos(), 1000);
+        assert_eq!(packet_buf.buf.len(), 1000);
+        assert_eq!(packet_buf.get(999), Ok(0xab));
+    }
+
+    #[test]
+    fn should_not_write_past_max_size() {
+        let mut packet_buf = BytePacketBuffer::new();
+        for _ in 0..MAX_UDP_SIZE {
+            packet_buf.write_u8(0).unwrap();
+        }
+
+        assert!(packet_buf.write_u8(0).is_err());
+        assert_eq!(packet_buf.pos(), MAX_UDP_SIZE);
+    }
+
+    #[test]
+    fn should_read_u32() {
+        let mut packet_buf = BytePacketBuffer::with_max_size(MAX_TCP_SIZE);
+        packet_buf.write_u32(0x0001_5180).unwrap();
+        packet_buf.seek(0).unwrap();
+
+        assert_eq!(packet_buf.read_u32(), Ok(86400));
+    }
+
+    #[test]
+    fn should_not_read_past_received_bytes() {
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0x01, 0x02]);
+
+        assert_eq!(packet_buf.read_u16(), Ok(0x0102));
+        assert!(packet_buf.read().is_err());
+        assert!(packet_buf.read_range(1, 2).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 mod buf;
 mod packet;
 
-pub use buf::BytePacketBuffer;
+pub use buf::{BytePacketBuffer, MAX_EDNS_SIZE, MAX_TCP_SIZE, MAX_UDP_SIZE};
 pub use packet::Packet;
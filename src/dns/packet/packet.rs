@@ -1,16 +1,21 @@
+use crate::dns::error::DnsError;
 use crate::dns::header::Header;
+use crate::dns::name::Name;
 use crate::dns::packet::BytePacketBuffer;
 use crate::dns::question::Question;
-use crate::dns::record::{QueryType, Record};
-use std::net::Ipv4Addr;
+use crate::dns::record::{Edns, QueryType, Record};
+use std::fmt;
+use std::net::IpAddr;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<Record>,
     pub authorities: Vec<Record>,
     pub additionals: Vec<Record>,
+    /// Whether `write` compresses repeated names, on by default.
+    pub compression: bool,
 }
 
 impl Packet {
@@ -21,15 +26,16 @@ impl Packet {
             answers: Vec::new(),
             authorities: Vec::new(),
             additionals: Vec::new(),
+            compression: true,
         }
     }
 
-    pub fn from_buf(buf: &mut BytePacketBuffer) -> Result<Packet, String> {
+    pub fn from_buf(buf: &mut BytePacketBuffer) -> Result<Packet, DnsError> {
         let mut packet = Packet::new();
         packet.header.read(buf)?;
 
         for _ in 0..packet.header.question_count {
-            let mut question = Question::new("".to_string(), QueryType::UNKNOWN(0));
+            let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
             question.read(buf)?;
             packet.questions.push(question);
         }
@@ -52,63 +58,249 @@ impl Packet {
         Ok(packet)
     }
 
-    pub fn write(&self, buf: &mut BytePacketBuffer) -> Result<(), String> {
-        self.header.write(buf)?;
+    /// Writes the packet, truncating it to fit the buffer's max size.
+    ///
+    /// Records that don't fit are dropped from the end: additionals go
+    /// first, and only losing answers or authorities sets the TC bit
+    /// (RFC 2181 section 9). The OPT record is always kept.
+    pub fn write(&self, buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
+        buf.set_compression(self.compression);
+        let start = buf.pos();
+        let mut header = self.header.clone();
+        header.write(buf)?;
 
         for e in self.questions.iter() {
             e.write(buf)?;
         }
+        header.question_count = self.questions.len() as u16;
 
-        for e in self.answers.iter() {
-            e.write(buf)?;
-        }
+        let edns = self.edns();
+        let opt_len = edns.map_or(0, |e| 11 + e.rdata_len() as usize);
+        let limit = buf.max_size().saturating_sub(opt_len);
 
-        for e in self.authorities.iter() {
-            e.write(buf)?;
+        let mut complete;
+        (header.answer_count, complete) = Packet::write_records(self.answers.iter(), buf, limit);
+        header.authority_count = 0;
+        header.additional_count = 0;
+        if complete {
+            (header.authority_count, complete) =
+                Packet::write_records(self.authorities.iter(), buf, limit);
+        }
+        if complete {
+            let additionals = self
+                .additionals
+                .iter()
+                .filter(|e| !matches!(e, Record::OPT(_)));
+            header.additional_count = Packet::write_records(additionals, buf, limit).0;
+        } else {
+            header.truncated_message = true;
         }
 
-        for e in self.additionals.iter() {
-            e.write(buf)?;
+        if let Some(edns) = edns {
+            Record::OPT(edns.clone()).write(buf)?;
+            header.additional_count += 1;
         }
 
+        let end = buf.pos();
+        buf.seek(start)?;
+        header.write(buf)?;
+        buf.seek(end)?;
+
         Ok(())
     }
 
-    pub fn resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.find_ns(qname)
-            .flat_map(|(_, host)| {
-                self.additionals.iter().filter_map(move |e| match e {
-                    Record::A { domain, ip, .. } if domain == host => Some(ip),
-                    _ => None,
-                })
+    /// Writes records until one doesn't fit below `limit`, returning how
+    /// many made it and whether all of them did. Records we can't
+    /// serialise are skipped.
+    fn write_records<'a>(
+        records: impl Iterator<Item = &'a Record>,
+        buf: &mut BytePacketBuffer,
+        limit: usize,
+    ) -> (u16, bool) {
+        let mut count = 0;
+        for e in records {
+            let pos = buf.pos();
+            let res = e.write(buf);
+            if let Err(DnsError::UnsupportedType { .. }) = res {
+                continue;
+            }
+            if res.is_err() || buf.pos() > limit {
+                // pos was reachable before, so truncating back can't fail
+                let _ = buf.truncate(pos);
+                return (count, false);
+            }
+            count += 1;
+        }
+        (count, true)
+    }
+
+    /// The EDNS(0) data from the OPT record in the additional section, if any.
+    pub fn edns(&self) -> Option<&Edns> {
+        self.additionals.iter().find_map(|e| match e {
+            Record::OPT(edns) => Some(edns),
+            _ => None,
+        })
+    }
+
+    /// Replaces the OPT record, keeping it the last additional record.
+    pub fn set_edns(&mut self, edns: Edns) {
+        self.additionals.retain(|e| !matches!(e, Record::OPT(_)));
+        self.additionals.push(Record::OPT(edns));
+    }
+
+    /// Hosts named by MX and NS data in the answer and authority sections,
+    /// whose addresses belong in the additional section.
+    pub fn additional_hosts(&self) -> Vec<&Name> {
+        let mut hosts = Vec::new();
+        for e in self.answers.iter().chain(self.authorities.iter()) {
+            let host = match e {
+                Record::MX { host, .. } | Record::NS { host, .. } => host,
+                _ => continue,
+            };
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+        hosts
+    }
+
+    /// A glue address for a nameserver of `qname`, IPv4 preferred.
+    pub fn resolved_ns(&self, qname: &Name) -> Option<IpAddr> {
+        self.resolved_ns_addrs(qname).first().copied()
+    }
+
+    /// Every glue address for the nameservers `qname` is delegated to,
+    /// IPv4 first.
+    pub fn resolved_ns_addrs(&self, qname: &Name) -> Vec<IpAddr> {
+        let glue = self.find_ns(qname).flat_map(|(_, host)| {
+            self.additionals.iter().filter_map(move |e| match e {
+                Record::A { domain, ip, .. } if domain == host => Some(IpAddr::V4(*ip)),
+                Record::AAAA { domain, ip, .. } if domain == host => Some(IpAddr::V6(*ip)),
+                _ => None,
             })
-            .map(|e| *e)
-            .next()
+        });
+
+        let mut addrs = Vec::new();
+        for addr in glue {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs.sort_by_key(|e| e.is_ipv6());
+        addrs
     }
 
-    pub fn unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+    pub fn unresolved_ns<'a>(&'a self, qname: &'a Name) -> Option<&'a Name> {
         self.find_ns(qname).map(|(_, host)| host).next()
     }
 
-    fn find_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    /// The hosts of every nameserver `qname` is delegated to.
+    pub fn ns_hosts<'a>(&'a self, qname: &'a Name) -> Vec<&'a Name> {
+        self.find_ns(qname).map(|(_, host)| host).collect()
+    }
+
+    /// The SOA record in the authority section, which is what a negative
+    /// answer carries.
+    pub fn soa(&self) -> Option<&Record> {
+        self.authorities
+            .iter()
+            .find(|e| matches!(e, Record::SOA { .. }))
+    }
+
+    /// The zone the authority section delegates `qname` to, if this is a
+    /// referral.
+    pub fn referral_zone<'a>(&'a self, qname: &'a Name) -> Option<&'a Name> {
+        self.find_ns(qname).map(|(zone, _)| zone).next()
+    }
+
+    fn find_ns<'a>(&'a self, qname: &'a Name) -> impl Iterator<Item = (&'a Name, &'a Name)> {
         self.authorities
             .iter()
             .filter_map(|e| match e {
-                Record::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
+                Record::NS { domain, host, .. } => Some((domain, host)),
                 _ => None,
             })
-            .filter(move |(domain, ..)| qname.ends_with(*domain))
+            .filter(move |(domain, ..)| qname.is_subdomain_of(domain))
+    }
+
+    /// An address from the answers, IPv4 preferred.
+    pub fn random_answer(&self) -> Option<IpAddr> {
+        prefer_ipv4(self.answer_addrs().into_iter())
     }
 
-    pub fn random_answer(&self) -> Option<Ipv4Addr> {
+    /// The addresses in the A and AAAA answers.
+    pub fn answer_addrs(&self) -> Vec<IpAddr> {
         self.answers
             .iter()
             .filter_map(|e| match e {
-                Record::A { ip, .. } => Some(ip),
+                Record::A { ip, .. } => Some(IpAddr::V4(*ip)),
+                Record::AAAA { ip, .. } => Some(IpAddr::V6(*ip)),
                 _ => None,
             })
-            .map(|e| *e)
-            .next()
+            .collect()
+    }
+}
+
+/// IPv4 works on more hosts than IPv6, so only use IPv6 when it's all we have.
+fn prefer_ipv4(addrs: impl Iterator<Item = IpAddr>) -> Option<IpAddr> {
+    let mut fallback = None;
+    for addr in addrs {
+        if addr.is_ipv4() {
+            return Some(addr);
+        }
+        fallback = fallback.or(Some(addr));
+    }
+    fallback
+}
+
+impl Default for Packet {
+    fn default() -> Self {
+        Packet::new()
+    }
+}
+
+impl fmt::Display for Packet {
+    /// Laid out like dig's output: the header, then each section that has
+    /// anything in it, with records in presentation format. The counts are
+    /// those of the sections, whatever the header says.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let additionals: Vec<&Record> = self
+            .additionals
+            .iter()
+            .filter(|e| !matches!(e, Record::OPT(_)))
+            .collect();
+
+        let mut header = self.header.clone();
+        header.question_count = self.questions.len() as u16;
+        header.answer_count = self.answers.len() as u16;
+        header.authority_count = self.authorities.len() as u16;
+        header.additional_count = self.additionals.len() as u16;
+        writeln!(f, "{}", header)?;
+
+        if let Some(edns) = self.edns() {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n{}\n", edns)?;
+        }
+        write!(f, "\n;; QUESTION SECTION:\n")?;
+        for question in self.questions.iter() {
+            writeln!(f, ";{}", question)?;
+        }
+
+        let sections = [
+            ("ANSWER", self.answers.iter().collect()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additionals),
+        ];
+        for (name, records) in sections.iter() {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n;; {} SECTION:\n", name)?;
+            for rec in records.iter() {
+                writeln!(f, "{}", rec)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -116,7 +308,9 @@ impl Packet {
 mod test {
 
     use super::*;
-    use std::net::Ipv4Addr;
+    use crate::dns::packet::MAX_EDNS_SIZE;
+    use crate::dns::record::EdnsOption;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn should_write() {
@@ -134,19 +328,20 @@ mod test {
         header.answer_count = 1;
 
         for _ in 0..header.question_count {
-            let q = Question::new("google.com".to_string(), QueryType::A);
+            let q = Question::new("google.com".parse().unwrap(), QueryType::A);
             packet.questions.push(q);
         }
 
         for _ in 0..header.answer_count {
             packet.answers.push(Record::A {
-                domain: "google.com".to_string(),
+                domain: "google.com".parse().unwrap(),
                 ip: Ipv4Addr::new(93, 46, 8, 90),
                 ttl: 60,
             });
         }
 
         packet.header = header;
+        packet.compression = false;
 
         let mut packet_buf = BytePacketBuffer::new();
         let _ = packet.write(&mut packet_buf);
@@ -167,4 +362,298 @@ mod test {
             assert_eq!(&packet_buf.read().unwrap(), b);
         }
     }
+
+    #[test]
+    fn should_round_trip_more_than_512_bytes() {
+        let mut packet = Packet::new();
+        packet.header.id = 1;
+        packet.header.query_response = true;
+        packet.header.question_count = 1;
+        packet.header.answer_count = 40;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::A));
+        for i in 0..packet.header.answer_count {
+            packet.answers.push(Record::A {
+                domain: "google.com".parse().unwrap(),
+                ip: Ipv4Addr::new(10, 0, 0, i as u8),
+                ttl: 300,
+            });
+        }
+
+        let mut packet_buf = BytePacketBuffer::with_max_size(MAX_EDNS_SIZE);
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+        assert!(len > 512);
+
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert_eq!(read.answers.len(), 40);
+        assert_eq!(read.answers[39], packet.answers[39]);
+    }
+
+    #[test]
+    fn should_round_trip_edns() {
+        let mut packet = Packet::new();
+        packet.header.id = 7;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::A));
+
+        let mut edns = Edns::new(4096);
+        edns.dnssec_ok = true;
+        edns.options.push(EdnsOption {
+            code: 10,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        });
+        packet.set_edns(edns.clone());
+        packet.set_edns(edns.clone());
+
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert_eq!(read.header.additional_count, 1);
+        assert_eq!(read.edns(), Some(&edns));
+    }
+
+    #[test]
+    fn should_truncate_answers_and_set_tc() {
+        let mut packet = Packet::new();
+        packet.header.id = 1;
+        packet.header.query_response = true;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::A));
+        for i in 0..40 {
+            packet.answers.push(Record::A {
+                domain: "google.com".parse().unwrap(),
+                ip: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            });
+        }
+        packet.set_edns(Edns::new(512));
+
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+        assert!(len <= 512);
+
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert!(read.header.truncated_message);
+        assert!(!read.answers.is_empty() && read.answers.len() < 40);
+        assert_eq!(read.answers[0], packet.answers[0]);
+        assert_eq!(read.edns(), packet.edns());
+    }
+
+    #[test]
+    fn should_drop_additionals_without_tc() {
+        let mut packet = Packet::new();
+        packet.header.query_response = true;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::NS));
+        packet.answers.push(Record::NS {
+            domain: "google.com".parse().unwrap(),
+            host: "ns1.google.com".parse().unwrap(),
+            ttl: 300,
+        });
+        for i in 0..40 {
+            packet.additionals.push(Record::A {
+                domain: "ns1.google.com".parse().unwrap(),
+                ip: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            });
+        }
+
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert!(!read.header.truncated_message);
+        assert_eq!(read.answers, packet.answers);
+        assert!(read.additionals.len() < 40);
+    }
+
+    #[test]
+    fn should_write_compressed() {
+        let mut packet = Packet::new();
+        packet.header.id = 35419;
+        packet.header.query_response = true;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::NS));
+        packet.answers.push(Record::NS {
+            domain: "google.com".parse().unwrap(),
+            host: "ns1.google.com".parse().unwrap(),
+            ttl: 60,
+        });
+
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+
+        let buf = [
+            0xc0, 0x0c, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x06, 0x03, 0x6e,
+            0x73, 0x31, 0xc0, 0x0c,
+        ];
+        assert_eq!(packet_buf.pos(), 28 + buf.len());
+        assert_eq!(packet_buf.read_range(28, buf.len()).unwrap(), &buf[..]);
+
+        let len = packet_buf.pos();
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert_eq!(read.answers, packet.answers);
+    }
+
+    #[test]
+    fn should_survive_mutated_packets() {
+        let mut packet = Packet::new();
+        packet.questions.push(Question::new(
+            "www.google.com".parse().unwrap(),
+            QueryType::A,
+        ));
+        packet.answers.push(Record::CNAME {
+            domain: "www.google.com".parse().unwrap(),
+            host: "google.com".parse().unwrap(),
+            ttl: 60,
+        });
+        packet.set_edns(Edns::new(4096));
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+        let original = packet_buf.read_range(0, len).unwrap().to_vec();
+
+        // xorshift, so failures are reproducible
+        let mut seed: u32 = 0x2545_f491;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+
+        for _ in 0..10000 {
+            let mut data = original.clone();
+            for _ in 0..(1 + next() % 4) {
+                let i = next() % data.len();
+                data[i] = next() as u8;
+            }
+            data.truncate(1 + next() % data.len());
+
+            let mut read_buf = BytePacketBuffer::from_bytes(&data);
+            if let Ok(read) = Packet::from_buf(&mut read_buf) {
+                let mut write_buf = BytePacketBuffer::with_max_size(MAX_EDNS_SIZE);
+                let _ = read.write(&mut write_buf);
+            }
+        }
+    }
+
+    #[test]
+    fn should_resolve_ns_from_ipv6_glue() {
+        let mut packet = Packet::new();
+        packet.authorities.push(Record::NS {
+            domain: "google.com".parse().unwrap(),
+            host: "ns1.google.com".parse().unwrap(),
+            ttl: 60,
+        });
+        packet.additionals.push(Record::AAAA {
+            domain: "ns1.google.com".parse().unwrap(),
+            ip: "2001:4860:4802:32::a".parse().unwrap(),
+            ttl: 60,
+        });
+        let qname = "www.google.com".parse().unwrap();
+
+        assert_eq!(
+            packet.resolved_ns(&qname),
+            Some(IpAddr::V6("2001:4860:4802:32::a".parse().unwrap()))
+        );
+
+        packet.additionals.push(Record::A {
+            domain: "ns1.google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(216, 239, 32, 10),
+            ttl: 60,
+        });
+
+        assert_eq!(
+            packet.resolved_ns(&qname),
+            Some(IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10)))
+        );
+        assert_eq!(
+            packet.resolved_ns_addrs(&qname),
+            [
+                IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10)),
+                IpAddr::V6("2001:4860:4802:32::a".parse().unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn should_round_trip_aaaa() {
+        let mut packet = Packet::new();
+        packet.answers.push(Record::AAAA {
+            domain: "google.com".parse().unwrap(),
+            ip: Ipv6Addr::new(0x2404, 0x6800, 0x4003, 0xc00, 0, 0, 0, 0x8a),
+            ttl: 300,
+        });
+
+        let mut packet_buf = BytePacketBuffer::new();
+        packet.write(&mut packet_buf).unwrap();
+        let len = packet_buf.pos();
+
+        let mut read_buf = BytePacketBuffer::from_bytes(packet_buf.read_range(0, len).unwrap());
+        let read = Packet::from_buf(&mut read_buf).unwrap();
+
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(
+            read.random_answer(),
+            Some(IpAddr::V6(Ipv6Addr::new(
+                0x2404, 0x6800, 0x4003, 0xc00, 0, 0, 0, 0x8a
+            )))
+        );
+    }
+
+    #[test]
+    fn should_display_like_dig() {
+        let mut packet = Packet::new();
+        packet.header.id = 35419;
+        packet.header.query_response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet
+            .questions
+            .push(Question::new("google.com".parse().unwrap(), QueryType::A));
+        packet.answers.push(Record::A {
+            domain: "google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(93, 46, 8, 90),
+            ttl: 60,
+        });
+        packet.set_edns(Edns::new(MAX_EDNS_SIZE as u16));
+
+        assert_eq!(
+            packet.to_string(),
+            "\
+;; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 35419
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags:; udp: 4096
+
+;; QUESTION SECTION:
+;google.com. IN A
+
+;; ANSWER SECTION:
+google.com. 60 IN A 93.46.8.90
+"
+        );
+    }
 }
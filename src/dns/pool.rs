@@ -0,0 +1,102 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed from a bounded queue, so a burst of
+/// queries can't spawn an unbounded number of threads.
+pub struct ThreadPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// A pool of `size` workers with room for `queue` jobs waiting on them.
+    pub fn new(size: usize, queue: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        // the pool was dropped
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues `job` for the next free worker. Returns false, dropping the
+    /// job, if the queue is full.
+    pub fn try_execute<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.as_ref().unwrap().try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the workers finish what's queued, then waits for them.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn should_run_jobs() {
+        let (sender, receiver) = channel();
+        {
+            let pool = ThreadPool::new(4, 16);
+            for i in 0..16 {
+                let sender = sender.clone();
+                assert!(pool.try_execute(move || sender.send(i).unwrap()));
+            }
+        }
+
+        let mut done: Vec<i32> = receiver.try_iter().collect();
+        done.sort_unstable();
+        assert_eq!(done, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn should_drop_jobs_when_queue_is_full() {
+        let pool = ThreadPool::new(1, 1);
+        let (block, blocked) = channel::<()>();
+        let (started, start) = channel();
+
+        // keep the only worker busy, then fill the queue
+        assert!(pool.try_execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        }));
+        start.recv().unwrap();
+        assert!(pool.try_execute(|| {}));
+
+        assert!(!pool.try_execute(|| {}));
+        block.send(()).unwrap();
+    }
+}
@@ -1,29 +1,30 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::Name;
 use crate::dns::packet::BytePacketBuffer;
 use crate::dns::record::{Domain, QueryType};
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Question {
-    pub name: String,
+    pub name: Name,
     pub qtype: QueryType,
 }
 
 impl Question {
-    pub fn new(name: String, qtype: QueryType) -> Self {
+    pub fn new(name: Name, qtype: QueryType) -> Self {
         Question { name, qtype }
     }
 
-    pub fn read(&mut self, packet_buf: &mut BytePacketBuffer) -> Result<(), String> {
-        packet_buf.seek(12)?;
-        self.name = Domain::new("".to_string()).read(packet_buf)?;
-        self.qtype = QueryType::value_of(packet_buf.read_u16()? & 0xFF);
+    pub fn read(&mut self, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
+        self.name = Domain::read(packet_buf)?;
+        self.qtype = QueryType::value_of(packet_buf.read_u16()?);
         packet_buf.read_u16()?;
 
         Ok(())
     }
 
-    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), String> {
-        packet_buf.seek(12)?;
-        Domain::new(self.name.clone()).write(packet_buf)?;
+    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
+        Domain::write(&self.name, packet_buf)?;
         packet_buf.write_u16(self.qtype.num_value())?;
         packet_buf.write_u16(1)?;
 
@@ -31,6 +32,13 @@ impl Question {
     }
 }
 
+impl fmt::Display for Question {
+    /// `google.com. IN A`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} IN {}", self.name, self.qtype)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -38,7 +46,7 @@ mod test {
 
     #[test]
     fn should_create() {
-        let question = Question::new(String::from("github.com"), QueryType::A);
+        let question = Question::new("github.com".parse().unwrap(), QueryType::A);
 
         assert_eq!(question.name, "github.com");
         assert_eq!(question.qtype, QueryType::A);
@@ -47,7 +55,8 @@ mod test {
     #[test]
     fn should_read() {
         let mut packet_buf = create_packet_buffer();
-        let mut question = Question::new("".to_string(), QueryType::UNKNOWN(0));
+        let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
+        packet_buf.seek(12).unwrap();
         let _ = question.read(&mut packet_buf);
 
         assert_eq!(question.name, "google.com");
@@ -57,8 +66,9 @@ mod test {
     #[test]
     fn should_write() {
         let mut packet_buf = BytePacketBuffer::new();
-        let question = Question::new("google.com".to_string(), QueryType::A);
+        let question = Question::new("google.com".parse().unwrap(), QueryType::A);
 
+        packet_buf.seek(12).unwrap();
         let _ = question.write(&mut packet_buf);
         let buf = [
             0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01,
@@ -71,6 +81,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn should_read_qtype_above_255() {
+        let mut packet_buf = BytePacketBuffer::new();
+        let question = Question::new("example.com".parse().unwrap(), QueryType::UNKNOWN(257));
+        question.write(&mut packet_buf).unwrap();
+
+        let mut read = Question::new(Name::root(), QueryType::UNKNOWN(0));
+        packet_buf.seek(0).unwrap();
+        read.read(&mut packet_buf).unwrap();
+
+        assert_eq!(read.name, "example.com");
+        assert_eq!(read.qtype, QueryType::UNKNOWN(257));
+    }
+
     fn create_packet_buffer() -> BytePacketBuffer {
         let buffer = [
             0x8a, 0x5b, 0x85, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x06, 0x67,
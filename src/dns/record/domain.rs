@@ -1,29 +1,49 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::{Name, MAX_NAME_LEN};
 use crate::dns::packet::BytePacketBuffer;
 
-pub struct Domain {
-    name: String,
-}
+/// Upper bound on compression pointers followed for a single name.
+const MAX_JUMPS: usize = 20;
 
-impl Domain {
-    pub fn new(name: String) -> Domain {
-        Domain { name }
-    }
+/// Wire codec for `Name`s, following and emitting compression pointers.
+pub struct Domain;
 
-    pub fn read(&self, packet_buf: &mut BytePacketBuffer) -> Result<String, String> {
-        let mut result = String::new();
-        let mut jumped = false;
-        let mut pos = packet_buf.pos();
+impl Domain {
+    /// Reads a possibly compressed name.
+    ///
+    /// Every pointer has to go further back than the one before it, so a
+    /// malicious packet can't make us loop; on top of that we give up
+    /// after `MAX_JUMPS` pointers or once the name passes 255 bytes.
+    pub fn read(packet_buf: &mut BytePacketBuffer) -> Result<Name, DnsError> {
+        let start = packet_buf.pos();
+        let mut labels = Vec::new();
+        let mut name_len = 1;
+        let mut jumps = 0;
+        // lowest offset we may still jump to
+        let mut jump_limit = start;
+        let mut pos = start;
         loop {
             let len = packet_buf.get(pos)?;
+            // 0x40 and 0x80 prefixes are reserved label types
+            if len & 0xc0 == 0x40 || len & 0xc0 == 0x80 {
+                return Err(DnsError::MalformedLabel { offset: pos });
+            }
+
             if len & 0xc0 == 0xc0 {
-                if !jumped {
+                if jumps == 0 {
                     packet_buf.seek(pos + 2)?;
-                    jumped = true;
                 }
 
                 let b1 = (len as u16) << 8;
                 let b2 = packet_buf.get(pos + 1)? as u16;
-                pos = ((b1 | b2) ^ 0xc000) as usize;
+                let target = ((b1 | b2) ^ 0xc000) as usize;
+
+                jumps += 1;
+                if target >= jump_limit || jumps > MAX_JUMPS {
+                    return Err(DnsError::CompressionLoop { offset: pos });
+                }
+                jump_limit = target;
+                pos = target;
 
                 continue;
             }
@@ -33,28 +53,45 @@ impl Domain {
                 break;
             }
 
-            if result.len() > 0 {
-                result.push_str(".");
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LEN {
+                return Err(DnsError::MalformedLabel { offset: pos - 1 });
             }
 
-            result.push_str(&String::from_utf8_lossy(
-                packet_buf.read_range(pos, len as usize)?,
-            ));
+            labels.push(packet_buf.read_range(pos, len as usize)?.to_vec());
             pos += len as usize;
         }
 
-        if !jumped {
+        if jumps == 0 {
             packet_buf.seek(pos)?;
         }
 
-        Ok(result)
+        Name::from_labels(labels).map_err(|_| DnsError::MalformedLabel { offset: start })
     }
 
-    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), String> {
-        self.name.to_string().split(".").for_each(|e| {
-            packet_buf.write_u8(e.len() as u8).unwrap();
-            e.bytes().for_each(|b| packet_buf.write_u8(b).unwrap());
-        });
+    /// Writes `name`, pointing back at the longest suffix already in the
+    /// packet when the buffer has compression turned on.
+    pub fn write(name: &Name, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
+        for suffix in name.ancestors() {
+            if suffix.is_root() {
+                break;
+            }
+
+            if let Some(pos) = packet_buf.find_name(&suffix) {
+                packet_buf.write_u16(0xc000 | pos as u16)?;
+                return Ok(());
+            }
+
+            let pos = packet_buf.pos();
+            packet_buf.save_name(&suffix, pos);
+
+            // a Name never holds a label longer than 63 bytes
+            let label = suffix.labels().next().unwrap_or_default();
+            packet_buf.write_u8(label.len() as u8)?;
+            for b in label.iter() {
+                packet_buf.write_u8(*b)?;
+            }
+        }
 
         packet_buf.write_u8(0)?;
 
@@ -72,9 +109,7 @@ mod test {
         let mut packet_buffer = create_packet_buffer();
         packet_buffer.seek(12).unwrap();
 
-        let domain = Domain::new("".to_string())
-            .read(&mut packet_buffer)
-            .unwrap();
+        let domain = Domain::read(&mut packet_buffer).unwrap();
 
         assert_eq!(domain, "google.com");
     }
@@ -84,9 +119,7 @@ mod test {
         let mut packet_buffer = create_packet_buffer();
         packet_buffer.seek(28).unwrap();
 
-        let domain = Domain::new("".to_string())
-            .read(&mut packet_buffer)
-            .unwrap();
+        let domain = Domain::read(&mut packet_buffer).unwrap();
 
         assert_eq!(domain, "google.com");
     }
@@ -94,8 +127,8 @@ mod test {
     #[test]
     fn should_write() {
         let mut packet_buf = BytePacketBuffer::new();
-        let domain = Domain::new("google.com".to_string());
-        let _ = domain.write(&mut packet_buf);
+        let domain = "google.com".parse().unwrap();
+        let _ = Domain::write(&domain, &mut packet_buf);
 
         let buf = [
             0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00,
@@ -122,4 +155,112 @@ mod test {
 
         packet_buffer
     }
+
+    #[test]
+    fn should_write_compressed() {
+        let mut packet_buf = BytePacketBuffer::new();
+        packet_buf.set_compression(true);
+        packet_buf.seek(12).unwrap();
+        let google: Name = "google.com".parse().unwrap();
+        Domain::write(&google, &mut packet_buf).unwrap();
+        Domain::write(&"www.google.com".parse().unwrap(), &mut packet_buf).unwrap();
+        Domain::write(&google, &mut packet_buf).unwrap();
+
+        let buf = [0x03, 0x77, 0x77, 0x77, 0xc0, 0x0c, 0xc0, 0x0c];
+        assert_eq!(packet_buf.pos(), 24 + buf.len());
+
+        packet_buf.seek(24).unwrap();
+        for e in buf.iter() {
+            assert_eq!(&packet_buf.read().unwrap(), e);
+        }
+
+        packet_buf.seek(24).unwrap();
+        let domain = Domain::read(&mut packet_buf).unwrap();
+        assert_eq!(domain, "www.google.com");
+    }
+
+    #[test]
+    fn should_write_root() {
+        let mut packet_buf = BytePacketBuffer::new();
+        Domain::write(&Name::root(), &mut packet_buf).unwrap();
+
+        assert_eq!(packet_buf.pos(), 1);
+    }
+
+    #[test]
+    fn should_reject_reserved_label_type() {
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0x03, 0x63, 0x6f, 0x6d, 0x41, 0x00]);
+
+        assert_eq!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::MalformedLabel { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn should_report_where_name_ends_early() {
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0x06, 0x67, 0x6f, 0x6f]);
+
+        assert_eq!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::TruncatedMessage { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn should_reject_pointer_to_itself() {
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0x00, 0x00, 0xc0, 0x02]);
+        packet_buf.seek(2).unwrap();
+
+        assert_eq!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::CompressionLoop { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn should_reject_forward_pointer() {
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0xc0, 0x02, 0x00]);
+
+        assert_eq!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::CompressionLoop { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn should_reject_loop_through_earlier_label() {
+        // 0: "a" then a pointer back to 0, reached through a pointer at 4
+        let mut packet_buf = BytePacketBuffer::from_bytes(&[0x01, 0x61, 0xc0, 0x00, 0xc0, 0x00]);
+        packet_buf.seek(4).unwrap();
+
+        assert_eq!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::CompressionLoop { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn should_reject_name_longer_than_255() {
+        // 5 labels of 63 bytes, each pointing back at the one before
+        let mut bytes = Vec::new();
+        let mut starts: Vec<usize> = Vec::new();
+        for _ in 0..5 {
+            let start = bytes.len();
+            bytes.push(63);
+            bytes.extend_from_slice(&[0x61; 63]);
+            match starts.last() {
+                Some(prev) => bytes.extend_from_slice(&[0xc0, *prev as u8]),
+                None => bytes.push(0),
+            }
+            starts.push(start);
+        }
+        let mut packet_buf = BytePacketBuffer::from_bytes(&bytes);
+        packet_buf.seek(starts[4]).unwrap();
+
+        assert!(matches!(
+            Domain::read(&mut packet_buf),
+            Err(DnsError::MalformedLabel { .. })
+        ));
+    }
 }
@@ -0,0 +1,102 @@
+use crate::dns::error::DnsError;
+use crate::dns::packet::BytePacketBuffer;
+use std::fmt;
+
+/// EDNS(0) data carried by the OPT pseudo-record (RFC 6891).
+///
+/// The OPT record reuses the CLASS field for the UDP payload size and
+/// the TTL field for the extended RCODE, version and flags.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Reads the RDATA of an OPT record whose CLASS and TTL have already been consumed.
+    pub fn read(
+        class: u16,
+        ttl: u32,
+        len: u16,
+        packet_buf: &mut BytePacketBuffer,
+    ) -> Result<Edns, DnsError> {
+        let mut edns = Edns::new(class);
+        edns.extended_rcode = (ttl >> 24) as u8;
+        edns.version = (ttl >> 16) as u8;
+        edns.dnssec_ok = (ttl & (1 << 15)) > 0;
+
+        let end = packet_buf.pos() + len as usize;
+        while packet_buf.pos() < end {
+            let code = packet_buf.read_u16()?;
+            let option_len = packet_buf.read_u16()? as usize;
+            let data = packet_buf
+                .read_range(packet_buf.pos(), option_len)?
+                .to_vec();
+            packet_buf.seek(packet_buf.pos() + option_len)?;
+            edns.options.push(EdnsOption { code, data });
+        }
+
+        // an option ran past the RDLENGTH of the record
+        if packet_buf.pos() != end {
+            return Err(DnsError::TruncatedMessage { offset: end });
+        }
+
+        Ok(edns)
+    }
+
+    /// Value of the TTL field on the wire.
+    pub fn ttl(&self) -> u32 {
+        ((self.extended_rcode as u32) << 24)
+            | ((self.version as u32) << 16)
+            | ((self.dnssec_ok as u32) << 15)
+    }
+
+    /// Length of the RDATA on the wire.
+    pub fn rdata_len(&self) -> u16 {
+        self.options.iter().map(|e| 4 + e.data.len() as u16).sum()
+    }
+
+    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
+        for option in self.options.iter() {
+            packet_buf.write_u16(option.code)?;
+            packet_buf.write_u16(option.data.len() as u16)?;
+            for b in option.data.iter() {
+                packet_buf.write_u8(*b)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Edns {
+    /// The way dig shows it in the OPT pseudosection.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "; EDNS: version: {}, flags:{}; udp: {}",
+            self.version,
+            if self.dnssec_ok { " do" } else { "" },
+            self.udp_payload_size
+        )
+    }
+}
@@ -1,5 +1,7 @@
 mod domain;
+mod edns;
 mod record;
 
 pub use domain::Domain;
+pub use edns::{Edns, EdnsOption};
 pub use record::*;
@@ -1,41 +1,71 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::Name;
 use crate::dns::packet::BytePacketBuffer;
 use crate::dns::record::domain::Domain;
-use std::net::Ipv4Addr;
+use crate::dns::record::edns::Edns;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 #[warn(dead_code)]
 pub enum Record {
     UNKNOWN {
-        domain: String,
+        domain: Name,
         qtype: u16,
         ttl: u32,
         len: u16,
     },
     A {
-        domain: String,
+        domain: Name,
         ip: Ipv4Addr,
         ttl: u32,
     },
     NS {
-        domain: String,
-        host: String,
+        domain: Name,
+        host: Name,
         ttl: u32,
     },
     CNAME {
-        domain: String,
-        host: String,
+        domain: Name,
+        host: Name,
         ttl: u32,
     },
+    SOA {
+        domain: Name,
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    MX {
+        domain: Name,
+        priority: u16,
+        host: Name,
+        ttl: u32,
+    },
+    AAAA {
+        domain: Name,
+        ip: Ipv6Addr,
+        ttl: u32,
+    },
+    OPT(Edns),
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
     MX,    // 15
     AAAA,  // 28
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -44,8 +74,10 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -55,22 +87,172 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
             QueryType::UNKNOWN(n) => n,
         }
     }
 }
 
+impl fmt::Display for QueryType {
+    /// The mnemonic, or `TYPE<n>` for types we don't know (RFC 3597).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryType::A => f.write_str("A"),
+            QueryType::NS => f.write_str("NS"),
+            QueryType::CNAME => f.write_str("CNAME"),
+            QueryType::SOA => f.write_str("SOA"),
+            QueryType::MX => f.write_str("MX"),
+            QueryType::AAAA => f.write_str("AAAA"),
+            QueryType::OPT => f.write_str("OPT"),
+            QueryType::UNKNOWN(n) => write!(f, "TYPE{}", n),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    /// The mnemonic in any case, or `TYPE<n>`.
+    fn from_str(s: &str) -> Result<QueryType, String> {
+        let qtype = match s.to_ascii_uppercase().as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "MX" => QueryType::MX,
+            "AAAA" => QueryType::AAAA,
+            "OPT" => QueryType::OPT,
+            e => match e.strip_prefix("TYPE").map(|n| n.parse::<u16>()) {
+                Some(Ok(n)) => QueryType::value_of(n),
+                _ => return Err(format!("unknown type {}", s)),
+            },
+        };
+        Ok(qtype)
+    }
+}
+
+impl fmt::Display for Record {
+    /// Presentation format, one line as in a master file:
+    /// `google.com. 60 IN A 142.250.4.100`. Records whose data we don't
+    /// keep, and OPT, come out as comments.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::UNKNOWN {
+                domain,
+                qtype,
+                ttl,
+                len,
+            } => write!(
+                f,
+                "; {} {} IN TYPE{} ({} bytes of data not kept)",
+                domain, ttl, qtype, len
+            ),
+            Record::OPT(edns) => write!(f, "{}", edns),
+            _ => write!(
+                f,
+                "{} {} IN {} {}",
+                self.domain().unwrap(),
+                self.ttl(),
+                self.qtype(),
+                self.rdata().unwrap_or_default()
+            ),
+        }
+    }
+}
+
 impl Record {
-    pub fn read(packet_buf: &mut BytePacketBuffer) -> Result<Record, String> {
-        let domain = Domain::new("".to_string()).read(packet_buf)?;
+    /// The owner name, `None` for OPT which doesn't really have one.
+    pub fn domain(&self) -> Option<&Name> {
+        match self {
+            Record::UNKNOWN { domain, .. }
+            | Record::A { domain, .. }
+            | Record::NS { domain, .. }
+            | Record::CNAME { domain, .. }
+            | Record::SOA { domain, .. }
+            | Record::MX { domain, .. }
+            | Record::AAAA { domain, .. } => Some(domain),
+            Record::OPT(_) => None,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            Record::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(*qtype),
+            Record::A { .. } => QueryType::A,
+            Record::NS { .. } => QueryType::NS,
+            Record::CNAME { .. } => QueryType::CNAME,
+            Record::SOA { .. } => QueryType::SOA,
+            Record::MX { .. } => QueryType::MX,
+            Record::AAAA { .. } => QueryType::AAAA,
+            Record::OPT(_) => QueryType::OPT,
+        }
+    }
+
+    /// The data in presentation format, as in the last field of a master
+    /// file line. `None` for UNKNOWN, whose data we don't keep, and OPT.
+    pub fn rdata(&self) -> Option<String> {
+        let rdata = match self {
+            Record::A { ip, .. } => ip.to_string(),
+            Record::AAAA { ip, .. } => ip.to_string(),
+            Record::NS { host, .. } | Record::CNAME { host, .. } => host.to_string(),
+            Record::MX { priority, host, .. } => format!("{} {}", priority, host),
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            Record::UNKNOWN { .. } | Record::OPT(_) => return None,
+        };
+        Some(rdata)
+    }
+
+    /// The TTL in seconds, 0 for OPT whose TTL field means something else.
+    pub fn ttl(&self) -> u32 {
+        match self {
+            Record::UNKNOWN { ttl, .. }
+            | Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::AAAA { ttl, .. } => *ttl,
+            Record::OPT(_) => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            Record::UNKNOWN { ttl, .. }
+            | Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::AAAA { ttl, .. } => *ttl = new_ttl,
+            Record::OPT(_) => {}
+        }
+    }
+
+    pub fn read(packet_buf: &mut BytePacketBuffer) -> Result<Record, DnsError> {
+        let domain = Domain::read(packet_buf)?;
         let qtype = packet_buf.read_u16()?;
-        let _ = packet_buf.read_u16()?;
-        let ttl = packet_buf.read_u16()? as u32 | packet_buf.read_u16()? as u32;
+        let class = packet_buf.read_u16()?;
+        let ttl = packet_buf.read_u32()?;
         let len = packet_buf.read_u16()?;
+        let end = packet_buf.pos() + len as usize;
 
-        match QueryType::value_of(qtype) {
+        let record = match QueryType::value_of(qtype) {
             QueryType::A => {
                 let ip4_addr = Ipv4Addr::new(
                     packet_buf.read()?,
@@ -78,48 +260,97 @@ impl Record {
                     packet_buf.read()?,
                     packet_buf.read()?,
                 );
-                return Ok(Record::A {
-                    domain: domain,
+                Record::A {
+                    domain,
                     ip: ip4_addr,
-                    ttl: ttl,
-                });
+                    ttl,
+                }
             }
             QueryType::NS => {
-                let cname = Domain::new("".to_string()).read(packet_buf)?;
-                Ok(Record::NS {
-                    domain: domain,
+                let cname = Domain::read(packet_buf)?;
+                Record::NS {
+                    domain,
                     host: cname,
-                    ttl: ttl,
-                })
+                    ttl,
+                }
             }
             QueryType::CNAME => {
-                let cname = Domain::new("".to_string()).read(packet_buf)?;
-                Ok(Record::CNAME {
-                    domain: domain,
+                let cname = Domain::read(packet_buf)?;
+                Record::CNAME {
+                    domain,
                     host: cname,
-                    ttl: ttl,
-                })
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                let mname = Domain::read(packet_buf)?;
+                let rname = Domain::read(packet_buf)?;
+                Record::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: packet_buf.read_u32()?,
+                    refresh: packet_buf.read_u32()?,
+                    retry: packet_buf.read_u32()?,
+                    expire: packet_buf.read_u32()?,
+                    minimum: packet_buf.read_u32()?,
+                    ttl,
+                }
             }
+            QueryType::MX => {
+                let priority = packet_buf.read_u16()?;
+                let host = Domain::read(packet_buf)?;
+                Record::MX {
+                    domain,
+                    priority,
+                    host,
+                    ttl,
+                }
+            }
+            QueryType::AAAA => {
+                if len != 16 {
+                    return Err(DnsError::TruncatedMessage {
+                        offset: packet_buf.pos() + len as usize,
+                    });
+                }
+                let mut octets = [0; 16];
+                octets.copy_from_slice(packet_buf.read_range(packet_buf.pos(), 16)?);
+                packet_buf.seek(packet_buf.pos() + 16)?;
+                Record::AAAA {
+                    domain,
+                    ip: Ipv6Addr::from(octets),
+                    ttl,
+                }
+            }
+            QueryType::OPT => Record::OPT(Edns::read(class, ttl, len, packet_buf)?),
             _ => {
+                // make sure the skipped data is really there
+                packet_buf.read_range(packet_buf.pos(), len as usize)?;
                 packet_buf.seek(packet_buf.pos() + len as usize)?;
-                return Ok(Record::UNKNOWN {
-                    domain: domain,
-                    qtype: qtype,
-                    ttl: ttl,
-                    len: len,
-                });
+                Record::UNKNOWN {
+                    domain,
+                    qtype,
+                    ttl,
+                    len,
+                }
             }
+        };
+
+        // the fields have to fill the RDLENGTH exactly, no more and no less
+        if packet_buf.pos() != end {
+            return Err(DnsError::TruncatedMessage { offset: end });
         }
+
+        Ok(record)
     }
 
-    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), String> {
+    pub fn write(&self, packet_buf: &mut BytePacketBuffer) -> Result<(), DnsError> {
         match self {
             Record::A { domain, ip, ttl } => {
-                Domain::new(domain.into()).write(packet_buf)?;
+                Domain::write(domain, packet_buf)?;
                 packet_buf.write_u16(QueryType::A.num_value())?;
                 packet_buf.write_u16(1)?;
-                packet_buf.write_u16((ttl >> 16) as u16)?;
-                packet_buf.write_u16((ttl & 0xFF) as u16)?;
+                packet_buf.write_u32(*ttl)?;
 
                 packet_buf.write_u16(4)?;
 
@@ -130,30 +361,104 @@ impl Record {
                 packet_buf.write_u8(octets[3])?;
             }
             Record::NS { domain, host, ttl } => {
-                Domain::new(domain.into()).write(packet_buf)?;
+                Domain::write(domain, packet_buf)?;
                 packet_buf.write_u16(QueryType::NS.num_value())?;
                 packet_buf.write_u16(1)?;
-                packet_buf.write_u16((ttl >> 16) as u16)?;
-                packet_buf.write_u16((ttl & 0xFF) as u16)?;
-                // 2 = first len byte + end len byte(0x00)
-                let len = host.len() + 2;
-                packet_buf.write_u16(len as u16)?;
-                Domain::new(host.into()).write(packet_buf)?;
+                packet_buf.write_u32(*ttl)?;
+
+                // the host may be compressed, so fill in the length afterwards
+                let len_pos = packet_buf.pos();
+                packet_buf.write_u16(0)?;
+                Domain::write(host, packet_buf)?;
+                let len = packet_buf.pos() - (len_pos + 2);
+                packet_buf.set_u16(len_pos, len as u16)?;
             }
             Record::CNAME { domain, host, ttl } => {
-                Domain::new(domain.into()).write(packet_buf)?;
+                Domain::write(domain, packet_buf)?;
                 packet_buf.write_u16(QueryType::CNAME.num_value())?;
                 packet_buf.write_u16(1)?;
-                packet_buf.write_u16((ttl >> 16) as u16)?;
-                packet_buf.write_u16((ttl & 0xFF) as u16)?;
-                // 2 = first len byte + end len byte(0x00)
-                let len = host.len() + 2;
-                packet_buf.write_u16(len as u16)?;
-                Domain::new(host.into()).write(packet_buf)?;
+                packet_buf.write_u32(*ttl)?;
+
+                // the host may be compressed, so fill in the length afterwards
+                let len_pos = packet_buf.pos();
+                packet_buf.write_u16(0)?;
+                Domain::write(host, packet_buf)?;
+                let len = packet_buf.pos() - (len_pos + 2);
+                packet_buf.set_u16(len_pos, len as u16)?;
             }
+            Record::SOA {
+                domain,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                Domain::write(domain, packet_buf)?;
+                packet_buf.write_u16(QueryType::SOA.num_value())?;
+                packet_buf.write_u16(1)?;
+                packet_buf.write_u32(*ttl)?;
 
-            _ => {
-                println!("unknown record: {:?}", self);
+                let len_pos = packet_buf.pos();
+                packet_buf.write_u16(0)?;
+                Domain::write(mname, packet_buf)?;
+                Domain::write(rname, packet_buf)?;
+                packet_buf.write_u32(*serial)?;
+                packet_buf.write_u32(*refresh)?;
+                packet_buf.write_u32(*retry)?;
+                packet_buf.write_u32(*expire)?;
+                packet_buf.write_u32(*minimum)?;
+                let len = packet_buf.pos() - (len_pos + 2);
+                packet_buf.set_u16(len_pos, len as u16)?;
+            }
+            Record::MX {
+                domain,
+                priority,
+                host,
+                ttl,
+            } => {
+                Domain::write(domain, packet_buf)?;
+                packet_buf.write_u16(QueryType::MX.num_value())?;
+                packet_buf.write_u16(1)?;
+                packet_buf.write_u32(*ttl)?;
+
+                let len_pos = packet_buf.pos();
+                packet_buf.write_u16(0)?;
+                packet_buf.write_u16(*priority)?;
+                Domain::write(host, packet_buf)?;
+                let len = packet_buf.pos() - (len_pos + 2);
+                packet_buf.set_u16(len_pos, len as u16)?;
+            }
+            Record::AAAA { domain, ip, ttl } => {
+                Domain::write(domain, packet_buf)?;
+                packet_buf.write_u16(QueryType::AAAA.num_value())?;
+                packet_buf.write_u16(1)?;
+                packet_buf.write_u32(*ttl)?;
+
+                packet_buf.write_u16(16)?;
+
+                for b in ip.octets().iter() {
+                    packet_buf.write_u8(*b)?;
+                }
+            }
+            Record::OPT(edns) => {
+                // the owner name of an OPT record is always the root
+                packet_buf.write_u8(0)?;
+                packet_buf.write_u16(QueryType::OPT.num_value())?;
+                packet_buf.write_u16(edns.udp_payload_size)?;
+                packet_buf.write_u32(edns.ttl())?;
+                packet_buf.write_u16(edns.rdata_len())?;
+                edns.write(packet_buf)?;
+            }
+            Record::UNKNOWN { qtype, .. } => {
+                // we skipped its data when reading, so there's nothing to write
+                return Err(DnsError::UnsupportedType {
+                    offset: packet_buf.pos(),
+                    qtype: *qtype,
+                });
             }
         }
         Ok(())
@@ -174,7 +479,7 @@ mod test {
         assert_eq!(
             record,
             Record::A {
-                domain: "google.com".into(),
+                domain: "google.com".parse().unwrap(),
                 ip: Ipv4Addr::new(93, 46, 8, 90),
                 ttl: 60,
             }
@@ -190,8 +495,8 @@ mod test {
         assert_eq!(
             record,
             Record::CNAME {
-                domain: "google.com".into(),
-                host: "google.com".into(),
+                domain: "google.com".parse().unwrap(),
+                host: "google.com".parse().unwrap(),
                 ttl: 60,
             }
         );
@@ -201,7 +506,7 @@ mod test {
     fn should_write_a_record() {
         let mut packet_buf = BytePacketBuffer::new();
         let record = Record::A {
-            domain: "google.com".to_string(),
+            domain: "google.com".parse().unwrap(),
             ip: Ipv4Addr::new(93, 46, 8, 90),
             ttl: 60,
         };
@@ -223,8 +528,8 @@ mod test {
     fn should_write_cname_record() {
         let mut packet_buf = BytePacketBuffer::new();
         let record = Record::CNAME {
-            domain: "google.com".into(),
-            host: "google.com".into(),
+            domain: "google.com".parse().unwrap(),
+            host: "google.com".parse().unwrap(),
             ttl: 60,
         };
 
@@ -274,7 +579,7 @@ mod test {
             0x6f, 0x6f, // 00000010
             0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00,
             0x00, 0x3c, // 00000020
-            0x00, 0x0a, 0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d,
+            0x00, 0x0c, 0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d,
             0x00, // cname
             0x5d, 0x2e, 0x08, 0x5a, 0x8a, 0x5b, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00,
             0x00, // 00000030
@@ -291,4 +596,196 @@ mod test {
 
         packet_buffer
     }
+
+    #[test]
+    fn should_write_aaaa_record() {
+        let mut packet_buf = BytePacketBuffer::new();
+        let record = Record::AAAA {
+            domain: "google.com".parse().unwrap(),
+            ip: "2404:6800:4003:c00::8a".parse().unwrap(),
+            ttl: 60,
+        };
+
+        let _ = record.write(&mut packet_buf);
+
+        let buf = [
+            0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x1c,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x10, 0x24, 0x04, 0x68, 0x00, 0x40, 0x03,
+            0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x8a,
+        ];
+
+        packet_buf.seek(0).unwrap();
+        for b in buf.iter() {
+            assert_eq!(&packet_buf.read().unwrap(), b);
+        }
+
+        packet_buf.seek(0).unwrap();
+        assert_eq!(Record::read(&mut packet_buf).unwrap(), record);
+    }
+
+    #[test]
+    fn should_write_mx_record() {
+        let mut packet_buf = BytePacketBuffer::new();
+        let record = Record::MX {
+            domain: "google.com".parse().unwrap(),
+            priority: 10,
+            host: "smtp.google.com".parse().unwrap(),
+            ttl: 60,
+        };
+
+        let _ = record.write(&mut packet_buf);
+
+        let buf = [
+            0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x0f,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x13, 0x00, 0x0a, 0x04, 0x73, 0x6d, 0x74,
+            0x70, 0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00,
+        ];
+
+        packet_buf.seek(0).unwrap();
+        for b in buf.iter() {
+            assert_eq!(&packet_buf.read().unwrap(), b);
+        }
+
+        packet_buf.seek(0).unwrap();
+        assert_eq!(Record::read(&mut packet_buf).unwrap(), record);
+    }
+
+    fn soa_record() -> Record {
+        Record::SOA {
+            domain: "google.com".parse().unwrap(),
+            mname: "ns1.google.com".parse().unwrap(),
+            rname: "dns-admin.google.com".parse().unwrap(),
+            serial: 2024010101,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 60,
+        }
+    }
+
+    #[test]
+    fn should_write_compressed_soa_record() {
+        let mut packet_buf = BytePacketBuffer::new();
+        packet_buf.set_compression(true);
+        packet_buf.seek(12).unwrap();
+        let record = soa_record();
+
+        record.write(&mut packet_buf).unwrap();
+
+        let buf = [
+            0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x06,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x26, // rdlength
+            0x03, 0x6e, 0x73, 0x31, 0xc0, 0x0c, // mname
+            0x09, 0x64, 0x6e, 0x73, 0x2d, 0x61, 0x64, 0x6d, 0x69, 0x6e, 0xc0, 0x0c, // rname
+            0x78, 0xa3, 0xf1, 0x75, 0x00, 0x00, 0x03, 0x84, 0x00, 0x00, 0x03, 0x84, 0x00, 0x00,
+            0x07, 0x08, 0x00, 0x00, 0x00, 0x3c,
+        ];
+        assert_eq!(packet_buf.pos(), 12 + buf.len());
+
+        packet_buf.seek(12).unwrap();
+        for b in buf.iter() {
+            assert_eq!(&packet_buf.read().unwrap(), b);
+        }
+
+        packet_buf.seek(12).unwrap();
+        assert_eq!(Record::read(&mut packet_buf).unwrap(), record);
+    }
+
+    #[test]
+    fn should_display_presentation_format() {
+        let a = Record::A {
+            domain: "google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(93, 46, 8, 90),
+            ttl: 60,
+        };
+        let mx = Record::MX {
+            domain: "google.com".parse().unwrap(),
+            priority: 10,
+            host: "smtp.google.com".parse().unwrap(),
+            ttl: 300,
+        };
+
+        assert_eq!(a.to_string(), "google.com. 60 IN A 93.46.8.90");
+        assert_eq!(mx.to_string(), "google.com. 300 IN MX 10 smtp.google.com.");
+        assert_eq!(
+            soa_record().to_string(),
+            "google.com. 60 IN SOA ns1.google.com. dns-admin.google.com. 2024010101 900 900 1800 60"
+        );
+        assert_eq!(QueryType::UNKNOWN(99).to_string(), "TYPE99");
+        assert_eq!("aaaa".parse(), Ok(QueryType::AAAA));
+        assert_eq!("TYPE99".parse(), Ok(QueryType::UNKNOWN(99)));
+        assert_eq!("TYPE15".parse(), Ok(QueryType::MX));
+        assert!("AXFR".parse::<QueryType>().is_err());
+        assert_eq!(
+            Record::OPT(Edns::new(1232)).to_string(),
+            "; EDNS: version: 0, flags:; udp: 1232"
+        );
+    }
+
+    #[test]
+    fn should_reject_rdlength_mismatch() {
+        let mut packet_buf = BytePacketBuffer::new();
+        soa_record().write(&mut packet_buf).unwrap();
+
+        // one byte more than the fields take up, RDLENGTH comes after the
+        // owner name, type, class and TTL
+        let len_pos = 12 + 8;
+        packet_buf.seek(len_pos).unwrap();
+        let len = packet_buf.read_u16().unwrap();
+        packet_buf.set_u16(len_pos, len + 1).unwrap();
+
+        packet_buf.seek(0).unwrap();
+        assert_eq!(
+            Record::read(&mut packet_buf),
+            Err(DnsError::TruncatedMessage {
+                offset: len_pos + 2 + len as usize + 1
+            })
+        );
+    }
+
+    #[test]
+    fn should_keep_all_32_bits_of_ttl() {
+        // 86400 is 0x00015180, both halves have bits set
+        let record = Record::A {
+            domain: "google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(142, 250, 4, 100),
+            ttl: 86400,
+        };
+        let mut packet_buf = BytePacketBuffer::new();
+        record.write(&mut packet_buf).unwrap();
+
+        // the TTL comes after the owner name, type and class
+        packet_buf.seek(12 + 4).unwrap();
+        assert_eq!(packet_buf.read_u32().unwrap(), 86400);
+
+        packet_buf.seek(0).unwrap();
+        assert_eq!(Record::read(&mut packet_buf).unwrap(), record);
+    }
+
+    #[test]
+    fn should_reject_aaaa_with_wrong_rdlength() {
+        let record = Record::AAAA {
+            domain: "google.com".parse().unwrap(),
+            ip: "2404:6800:4003:c00::64".parse().unwrap(),
+            ttl: 300,
+        };
+        // RDLENGTH comes after the owner name, type, class and TTL
+        let len_pos = 12 + 8;
+
+        for len in [15, 17].iter() {
+            let mut packet_buf = BytePacketBuffer::new();
+            record.write(&mut packet_buf).unwrap();
+            packet_buf.write_u8(0).unwrap();
+            packet_buf.set_u16(len_pos, *len).unwrap();
+
+            packet_buf.seek(0).unwrap();
+            assert_eq!(
+                Record::read(&mut packet_buf),
+                Err(DnsError::TruncatedMessage {
+                    offset: len_pos + 2 + *len as usize
+                })
+            );
+        }
+    }
 }
@@ -0,0 +1,875 @@
+use crate::dns::error::DnsError;
+use crate::dns::header::ResponseCode;
+use crate::dns::name::Name;
+use crate::dns::packet::Packet;
+use crate::dns::record::{QueryType, Record};
+use crate::dns::server::{Server, Step, QUERY_RETRIES};
+use crate::dns::trace::{Hop, Trace};
+use log::{debug, warn};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// What a `Resolution` needs done next.
+pub(crate) enum Action {
+    /// Send `send_packet` to `server`, over TCP if `tcp` or else UDP, wait
+    /// up to `timeout` for the response and hand it to
+    /// `Resolution::received`.
+    Send {
+        send_packet: Packet,
+        server: (IpAddr, u16),
+        tcp: bool,
+        timeout: Duration,
+    },
+    /// The lookup is over, that's its result.
+    Done(Result<Packet, DnsError>),
+}
+
+/// A query we sent and are waiting to hear back about.
+struct Sent {
+    send_packet: Packet,
+    server: (IpAddr, u16),
+    tcp: bool,
+    timeout: Duration,
+    at: Instant,
+}
+
+/// How far the lookup of a frame's `name` has got.
+enum Phase {
+    /// Nowhere yet: next is the cache, or else the closest zone we know
+    /// servers for.
+    Start,
+    /// Starting from the root, but priming first.
+    Prime,
+    /// Asking `servers`, the servers for `zone`, in turn. `attempts` counts
+    /// the queries so far, retries included, and `result` is what the last
+    /// one got.
+    Query {
+        zone: Name,
+        servers: Vec<(IpAddr, u16)>,
+        attempts: usize,
+        result: Result<Packet, DnsError>,
+        sent: Option<Sent>,
+    },
+    /// `res_packet` referred us to `zone` without glue, so we're looking up
+    /// the addresses of `hosts`, A then AAAA for each, `attempts` lookups
+    /// in.
+    ResolveNs {
+        zone: Name,
+        hosts: Vec<Name>,
+        attempts: usize,
+        res_packet: Packet,
+    },
+}
+
+impl Phase {
+    fn query(zone: Name, servers: Vec<(IpAddr, u16)>) -> Phase {
+        Phase::Query {
+            zone,
+            servers,
+            attempts: 0,
+            result: Err(DnsError::Timeout),
+            sent: None,
+        }
+    }
+}
+
+/// Where a frame's lookup goes next.
+enum Outcome {
+    /// On, the phase has moved.
+    Continue,
+    /// Off to the network, nothing more to do until it answers.
+    Send(Action),
+    /// The address of a nameserver to look up first.
+    Push(Name, QueryType),
+    /// Priming to do first.
+    Prime,
+    /// A usable response from one of the zone's servers.
+    Answered(Packet),
+    /// Done with the current name, the chain may go on from here.
+    Resolved(Result<Packet, DnsError>),
+    /// Done with the frame.
+    Finished(Result<Packet, DnsError>),
+}
+
+/// One name being looked up, CNAMEs and all: the question we were asked,
+/// or a nameserver some referral needs the address of.
+struct Frame {
+    qname: Name,
+    qtype: QueryType,
+    /// The CNAMEs followed so far, and the name they lead to.
+    chain: Vec<Record>,
+    name: Name,
+    phase: Phase,
+    trace: Trace,
+}
+
+impl Frame {
+    fn new(qname: &Name, qtype: QueryType, trace: Trace) -> Frame {
+        Frame {
+            qname: qname.clone(),
+            qtype,
+            chain: Vec::new(),
+            name: qname.clone(),
+            phase: Phase::Start,
+            trace,
+        }
+    }
+
+    /// A frame asking `servers`, the servers for `zone`, straight away
+    /// rather than looking in the cache first.
+    fn asking(
+        qname: &Name,
+        qtype: QueryType,
+        zone: Name,
+        servers: Vec<(IpAddr, u16)>,
+        trace: Trace,
+    ) -> Frame {
+        let mut frame = Frame::new(qname, qtype, trace);
+        frame.phase = Phase::query(zone, servers);
+        frame
+    }
+
+    /// A frame asking the root servers we have for the current set of them.
+    fn priming(server: &Server, trace: Trace) -> Frame {
+        let root = Name::root();
+        let servers = server.root_servers().iter().map(|ip| (*ip, 53)).collect();
+        Frame::asking(&root, QueryType::NS, root.clone(), servers, trace)
+    }
+
+    fn step(
+        &mut self,
+        server: &Server,
+        response: &mut Option<Result<(Packet, usize), DnsError>>,
+        deadline: Instant,
+    ) -> Outcome {
+        let Frame {
+            name,
+            qtype,
+            phase,
+            trace,
+            ..
+        } = self;
+        let qtype = *qtype;
+
+        let (zone, servers, attempts, result, sent) = match phase {
+            Phase::Start => {
+                if let Some(res_packet) = server.cached_response(name, qtype) {
+                    trace.record(|| Hop::Cached {
+                        qname: name.clone(),
+                        qtype,
+                    });
+                    return Outcome::Resolved(Ok(res_packet));
+                }
+
+                let (zone, ns) = server.start_zone(name);
+                if zone.is_root() && server.priming_due() {
+                    *phase = Phase::Prime;
+                    return Outcome::Prime;
+                }
+                *phase = Phase::query(zone, ns.iter().map(|ip| (*ip, 53)).collect());
+                return Outcome::Continue;
+            }
+            Phase::Query {
+                zone,
+                servers,
+                attempts,
+                result,
+                sent,
+            } => (zone, servers, attempts, result, sent),
+            Phase::Prime | Phase::ResolveNs { .. } => {
+                unreachable!("stepped a frame waiting on another")
+            }
+        };
+
+        if let Some(sent_query) = sent.take() {
+            let response = response.take().unwrap_or(Err(DnsError::Timeout));
+            if let Ok((res_packet, _)) = &response {
+                if res_packet.header.truncated_message && !sent_query.tcp {
+                    let action = Action::Send {
+                        send_packet: sent_query.send_packet.clone(),
+                        server: sent_query.server,
+                        tcp: true,
+                        timeout: sent_query.timeout,
+                    };
+                    *sent = Some(Sent {
+                        tcp: true,
+                        ..sent_query
+                    });
+                    return Outcome::Send(action);
+                }
+            }
+
+            trace.record(|| Hop::Query {
+                zone: zone.clone(),
+                server: sent_query.server.0,
+                qname: name.clone(),
+                qtype,
+                rtt: sent_query.at.elapsed(),
+                response: response.clone(),
+            });
+            *attempts += 1;
+
+            let response = response.map(|e| e.0);
+            let usable = match &response {
+                Ok(res_packet) => !matches!(
+                    res_packet.header.response_code,
+                    ResponseCode::SERVFAIL | ResponseCode::REFUSED
+                ),
+                Err(e) => {
+                    warn!("lookup with ns {} failed: {}", sent_query.server.0, e);
+                    false
+                }
+            };
+            match response {
+                Ok(res_packet) if usable => return Outcome::Answered(res_packet),
+                response => *result = response,
+            }
+        }
+
+        // every server, QUERY_RETRIES more times round
+        if *attempts >= servers.len() * (QUERY_RETRIES + 1) {
+            return match mem::replace(result, Err(DnsError::Timeout)) {
+                Ok(res_packet) => Outcome::Answered(res_packet),
+                Err(e) => Outcome::Resolved(Err(e)),
+            };
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Outcome::Resolved(Err(DnsError::Timeout));
+        }
+        let send_packet = match Server::query_packet(name, qtype) {
+            Ok(send_packet) => send_packet,
+            Err(e) => return Outcome::Resolved(Err(e)),
+        };
+        let sent_query = Sent {
+            send_packet,
+            server: servers[*attempts % servers.len()],
+            tcp: false,
+            timeout: server.query_timeout.min(deadline - now),
+            at: now,
+        };
+        let action = Action::Send {
+            send_packet: sent_query.send_packet.clone(),
+            server: sent_query.server,
+            tcp: false,
+            timeout: sent_query.timeout,
+        };
+        *sent = Some(sent_query);
+
+        Outcome::Send(action)
+    }
+
+    /// Works out where `res_packet`, from one of the servers we're asking,
+    /// leaves the lookup.
+    fn answered(&mut self, server: &Server, res_packet: Packet) -> Outcome {
+        let (zone, result) = match &mut self.phase {
+            Phase::Query { zone, result, .. } => (zone.clone(), result),
+            _ => unreachable!("an answer without a query"),
+        };
+
+        match server.next_step(res_packet, &self.name, self.qtype, &zone) {
+            Step::Done(res_packet) => Outcome::Resolved(Ok(res_packet)),
+            Step::Lame => {
+                warn!("lame referral for {} from a server for {}", self.name, zone);
+                // on to the next server, as if this one hadn't answered
+                *result = Err(DnsError::UnexpectedResponse);
+                Outcome::Continue
+            }
+            Step::Referral {
+                zone,
+                addrs,
+                hosts,
+                res_packet,
+            } => {
+                self.trace.record(|| Hop::Referral {
+                    zone: zone.clone(),
+                    hosts: hosts.clone(),
+                    glue: addrs.clone(),
+                });
+
+                if !addrs.is_empty() {
+                    self.phase = Phase::query(zone, addrs.iter().map(|ip| (*ip, 53)).collect());
+                    return Outcome::Continue;
+                }
+                // without glue, a nameserver inside the zone could only be
+                // found by asking the zone itself
+                let hosts: Vec<Name> = hosts
+                    .into_iter()
+                    .filter(|e| !e.is_subdomain_of(&zone))
+                    .collect();
+                if hosts.is_empty() {
+                    return Outcome::Resolved(Ok(res_packet));
+                }
+
+                let host = hosts[0].clone();
+                self.phase = Phase::ResolveNs {
+                    zone,
+                    hosts,
+                    attempts: 0,
+                    res_packet,
+                };
+                Outcome::Push(host, NS_QTYPES[0])
+            }
+        }
+    }
+
+    /// Takes in `result`, what the lookup of `frame`, started on this
+    /// one's behalf, came to.
+    fn resume(
+        &mut self,
+        server: &Server,
+        frame: Frame,
+        result: Result<Packet, DnsError>,
+        deadline: Instant,
+    ) -> Outcome {
+        if !matches!(self.phase, Phase::Prime) {
+            return self.ns_resolved(frame, result, deadline);
+        }
+
+        match server.check_priming(result) {
+            Ok(()) => debug!("primed the root servers"),
+            Err(e) => warn!("priming failed, going on from the root hints: {}", e),
+        }
+        for hop in frame.trace.into_hops() {
+            self.trace.record(|| hop);
+        }
+        self.phase = Phase::Start;
+        Outcome::Continue
+    }
+
+    /// Takes in `result`, what the lookup of `ns_frame` came to, moving on
+    /// to the next nameserver if it didn't give any addresses.
+    fn ns_resolved(
+        &mut self,
+        ns_frame: Frame,
+        result: Result<Packet, DnsError>,
+        deadline: Instant,
+    ) -> Outcome {
+        let addrs = match &result {
+            Ok(res_packet) => res_packet.answer_addrs(),
+            Err(_) => Vec::new(),
+        };
+        let Frame {
+            qname: host,
+            qtype,
+            trace: ns_trace,
+            ..
+        } = ns_frame;
+        self.trace.record(|| Hop::ResolveNs {
+            host: host.clone(),
+            qtype,
+            hops: ns_trace.into_hops(),
+            addrs: addrs.clone(),
+        });
+
+        match result {
+            Ok(_) if !addrs.is_empty() => {
+                let zone = match &self.phase {
+                    Phase::ResolveNs { zone, .. } => zone.clone(),
+                    _ => unreachable!("a nameserver nobody was waiting on"),
+                };
+                self.phase = Phase::query(zone, addrs.iter().map(|ip| (*ip, 53)).collect());
+                return Outcome::Continue;
+            }
+            Ok(_) => {}
+            Err(DnsError::Timeout) if Instant::now() >= deadline => {
+                return Outcome::Resolved(Err(DnsError::Timeout));
+            }
+            Err(e) => warn!("resolving ns {} failed: {}", host, e),
+        }
+
+        self.next_ns()
+    }
+
+    /// Moves on to the next nameserver to look up, or gives up on them.
+    fn next_ns(&mut self) -> Outcome {
+        let (hosts, attempts, res_packet) = match &mut self.phase {
+            Phase::ResolveNs {
+                hosts,
+                attempts,
+                res_packet,
+                ..
+            } => (hosts, attempts, res_packet),
+            _ => unreachable!("a nameserver nobody was waiting on"),
+        };
+
+        *attempts += 1;
+        if *attempts == hosts.len() * NS_QTYPES.len() {
+            // none of them resolved, the referral is all we have
+            return Outcome::Resolved(Ok(res_packet.clone()));
+        }
+        Outcome::Push(
+            hosts[*attempts / NS_QTYPES.len()].clone(),
+            NS_QTYPES[*attempts % NS_QTYPES.len()],
+        )
+    }
+}
+
+/// What we look up for a nameserver, some only have IPv6 addresses.
+const NS_QTYPES: [QueryType; 2] = [QueryType::A, QueryType::AAAA];
+
+/// How many frames deep nameserver lookups may nest, the question we were
+/// asked included.
+const MAX_FRAMES: usize = 4;
+
+/// A recursive lookup with none of the I/O: it says what to send where,
+/// and the front end, blocking or async, does the sending. Retries,
+/// failover, referrals, nameserver lookups and CNAMEs are all dealt with
+/// here.
+pub(crate) struct Resolution {
+    /// The name we were asked about at the bottom, the nameserver lookups
+    /// it's waiting on above it.
+    frames: Vec<Frame>,
+    deadline: Instant,
+    response: Option<Result<(Packet, usize), DnsError>>,
+    hops: Vec<Hop>,
+}
+
+impl Resolution {
+    /// Resolves `qname` from the cache, or by walking down from the closest
+    /// zone we know servers for, and follows CNAMEs to where they lead.
+    pub(crate) fn new(
+        qname: &Name,
+        qtype: QueryType,
+        deadline: Instant,
+        trace: Trace,
+    ) -> Resolution {
+        Resolution::with_frame(Frame::new(qname, qtype, trace), deadline)
+    }
+
+    /// Like `new`, but asks `servers`, the servers for `zone`, rather than
+    /// looking in the cache first.
+    #[cfg(test)]
+    pub(crate) fn ask(
+        qname: &Name,
+        qtype: QueryType,
+        zone: Name,
+        servers: Vec<(IpAddr, u16)>,
+        deadline: Instant,
+        trace: Trace,
+    ) -> Resolution {
+        let frame = Frame::asking(qname, qtype, zone, servers, trace);
+        Resolution::with_frame(frame, deadline)
+    }
+
+    /// Primes the root servers, like `Server::prime`.
+    pub(crate) fn prime(server: &Server, deadline: Instant, trace: Trace) -> Resolution {
+        Resolution::with_frame(Frame::priming(server, trace), deadline)
+    }
+
+    fn with_frame(frame: Frame, deadline: Instant) -> Resolution {
+        Resolution {
+            frames: vec![frame],
+            deadline,
+            response: None,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Hands over the response to the last `Action::Send`, or why there was
+    /// none.
+    pub(crate) fn received(&mut self, response: Result<(Packet, usize), DnsError>) {
+        self.response = Some(response);
+    }
+
+    /// Moves the lookup on as far as it goes without the network, up to the
+    /// next query to send or the result.
+    pub(crate) fn poll(&mut self, server: &Server) -> Action {
+        let mut outcome = self.step(server);
+        loop {
+            outcome = match outcome {
+                Outcome::Continue => self.step(server),
+                Outcome::Send(action) => return action,
+                Outcome::Push(host, qtype) => {
+                    if self
+                        .frames
+                        .iter()
+                        .any(|e| e.qname == host || e.name == host)
+                    {
+                        // looking it up again would only lead back here
+                        warn!("ns {} needs itself resolved first, skipping it", host);
+                        self.top().next_ns()
+                    } else if self.frames.len() >= MAX_FRAMES {
+                        warn!("ns {} is too many lookups deep, skipping it", host);
+                        self.top().next_ns()
+                    } else {
+                        let trace = self.top().trace.nested();
+                        self.frames.push(Frame::new(&host, qtype, trace));
+                        self.step(server)
+                    }
+                }
+                Outcome::Prime => {
+                    let trace = self.top().trace.nested();
+                    self.frames.push(Frame::priming(server, trace));
+                    self.step(server)
+                }
+                Outcome::Answered(res_packet) => self.top().answered(server, res_packet),
+                Outcome::Resolved(Ok(res_packet)) => {
+                    let frame = self.top();
+                    match Server::chase(
+                        &frame.qname,
+                        frame.qtype,
+                        &mut frame.chain,
+                        &mut frame.name,
+                        res_packet,
+                    ) {
+                        Some(res_packet) => Outcome::Finished(Ok(res_packet)),
+                        None => {
+                            frame.phase = Phase::Start;
+                            self.step(server)
+                        }
+                    }
+                }
+                Outcome::Resolved(Err(e)) => Outcome::Finished(Err(e)),
+                Outcome::Finished(result) => {
+                    let frame = self.frames.pop().unwrap();
+                    match self.frames.last_mut() {
+                        Some(parent) => parent.resume(server, frame, result, self.deadline),
+                        None => {
+                            self.hops = frame.trace.into_hops();
+                            return Action::Done(result);
+                        }
+                    }
+                }
+            };
+        }
+    }
+
+    /// The hops taken, once it's done, if it was being traced.
+    pub(crate) fn into_hops(self) -> Vec<Hop> {
+        self.hops
+    }
+
+    fn top(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("polled a finished resolution")
+    }
+
+    fn step(&mut self, server: &Server) -> Outcome {
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("polled a finished resolution");
+        frame.step(server, &mut self.response, self.deadline)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// The response to `send_packet` with `records` in `answers` or
+    /// `authorities`.
+    fn response_to(send_packet: &Packet, answers: Vec<Record>, authorities: Vec<Record>) -> Packet {
+        let mut res_packet = Packet::new();
+        res_packet.header.id = send_packet.header.id;
+        res_packet.header.query_response = true;
+        res_packet.questions = send_packet.questions.clone();
+        res_packet.answers = answers;
+        res_packet.authorities = authorities;
+        res_packet
+    }
+
+    fn expect_send(action: Action) -> (Packet, (IpAddr, u16), bool) {
+        match action {
+            Action::Send {
+                send_packet,
+                server,
+                tcp,
+                ..
+            } => (send_packet, server, tcp),
+            Action::Done(result) => panic!("done early with {:?}", result),
+        }
+    }
+
+    #[test]
+    fn should_resolve_glueless_referral() {
+        let root_hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let ns_addr = Ipv4Addr::new(192, 0, 2, 53);
+        let mut server = Server::new();
+        server.set_root_hints(vec![root_hint]);
+        // as if priming had just been tried
+        assert!(server.priming_due());
+        let qname: Name = "www.example.com".parse().unwrap();
+        let mut resolution = Resolution::new(
+            &qname,
+            QueryType::A,
+            Instant::now() + Duration::from_secs(5),
+            Trace::new(),
+        );
+
+        // the root refers us to example.com, whose nameserver has no glue
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (root_hint, 53));
+        let ns = Record::NS {
+            domain: "example.com".parse().unwrap(),
+            host: "ns.example.net".parse().unwrap(),
+            ttl: 300,
+        };
+        resolution.received(Ok((response_to(&send_packet, vec![], vec![ns]), 100)));
+
+        // so we look it up, from the root again
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (root_hint, 53));
+        assert_eq!(send_packet.questions[0].name, "ns.example.net");
+        let ns_a = Record::A {
+            domain: "ns.example.net".parse().unwrap(),
+            ip: ns_addr,
+            ttl: 300,
+        };
+        resolution.received(Ok((response_to(&send_packet, vec![ns_a], vec![]), 100)));
+
+        // and ask it
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (IpAddr::V4(ns_addr), 53));
+        assert_eq!(send_packet.questions[0].name, qname);
+        let www_a = Record::A {
+            domain: qname.clone(),
+            ip: Ipv4Addr::new(192, 0, 2, 80),
+            ttl: 300,
+        };
+        resolution.received(Ok((
+            response_to(&send_packet, vec![www_a.clone()], vec![]),
+            100,
+        )));
+
+        match resolution.poll(&server) {
+            Action::Done(result) => assert_eq!(result.unwrap().answers, [www_a]),
+            Action::Send { .. } => panic!("one query too many"),
+        }
+        let hops = resolution.into_hops();
+        assert!(matches!(
+            &hops[..],
+            [
+                Hop::Query { .. },
+                Hop::Referral { .. },
+                Hop::ResolveNs { hops, addrs, .. },
+                Hop::Query { .. },
+            ] if hops.len() == 1 && *addrs == [IpAddr::V4(ns_addr)]
+        ));
+    }
+
+    #[test]
+    fn should_retry_truncated_response_over_tcp() {
+        let server = Server::new();
+        let ns = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+        let mut resolution = Resolution::ask(
+            &"google.com".parse().unwrap(),
+            QueryType::MX,
+            "com".parse().unwrap(),
+            vec![ns],
+            Instant::now() + Duration::from_secs(5),
+            Trace::new(),
+        );
+
+        let (send_packet, _, tcp) = expect_send(resolution.poll(&server));
+        assert!(!tcp);
+        let mut res_packet = response_to(&send_packet, vec![], vec![]);
+        res_packet.header.truncated_message = true;
+        resolution.received(Ok((res_packet, 512)));
+
+        let (tcp_packet, to, tcp) = expect_send(resolution.poll(&server));
+        assert!(tcp);
+        assert_eq!(to, ns);
+        assert_eq!(tcp_packet.header.id, send_packet.header.id);
+        resolution.received(Err(DnsError::Timeout));
+
+        // then on to the retry, over UDP again
+        let (_, _, tcp) = expect_send(resolution.poll(&server));
+        assert!(!tcp);
+        resolution.received(Err(DnsError::Timeout));
+        assert!(matches!(
+            resolution.poll(&server),
+            Action::Done(Err(DnsError::Timeout))
+        ));
+        // one hop a query, however many tries it took
+        assert_eq!(resolution.into_hops().len(), 2);
+    }
+
+    #[test]
+    fn should_not_resolve_ns_inside_zone_without_glue() {
+        let root_hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut server = Server::new();
+        server.set_root_hints(vec![root_hint]);
+        assert!(server.priming_due());
+        let mut resolution = Resolution::new(
+            &"www.example.com".parse().unwrap(),
+            QueryType::A,
+            Instant::now() + Duration::from_secs(5),
+            Trace::off(),
+        );
+
+        let (send_packet, _, _) = expect_send(resolution.poll(&server));
+        let ns = Record::NS {
+            domain: "example.com".parse().unwrap(),
+            host: "ns.example.com".parse().unwrap(),
+            ttl: 300,
+        };
+        resolution.received(Ok((response_to(&send_packet, vec![], vec![ns]), 100)));
+
+        // the referral is all we get
+        match resolution.poll(&server) {
+            Action::Done(result) => assert!(result.unwrap().answers.is_empty()),
+            Action::Send { send_packet, .. } => {
+                panic!("asked about {}", send_packet.questions[0].name)
+            }
+        }
+    }
+
+    #[test]
+    fn should_skip_ns_that_needs_itself_resolved() {
+        let root_hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut server = Server::new();
+        server.set_root_hints(vec![root_hint]);
+        assert!(server.priming_due());
+        let mut resolution = Resolution::new(
+            &"www.example.com".parse().unwrap(),
+            QueryType::A,
+            Instant::now() + Duration::from_secs(5),
+            Trace::off(),
+        );
+
+        // each zone's nameserver is in the other, without glue
+        let example_com: Name = "example.com".parse().unwrap();
+        let referral = |send_packet: &Packet| {
+            let (zone, host) = if send_packet.questions[0].name.is_subdomain_of(&example_com) {
+                ("example.com", "ns.example.net")
+            } else {
+                ("example.net", "ns.example.com")
+            };
+            let ns = Record::NS {
+                domain: zone.parse().unwrap(),
+                host: host.parse().unwrap(),
+                ttl: 300,
+            };
+            response_to(send_packet, vec![], vec![ns])
+        };
+
+        let mut queries = 0;
+        loop {
+            match resolution.poll(&server) {
+                Action::Send { send_packet, .. } => {
+                    assert!(resolution.frames.len() < MAX_FRAMES);
+                    queries += 1;
+                    resolution.received(Ok((referral(&send_packet), 100)));
+                }
+                Action::Done(result) => {
+                    assert!(result.unwrap().answers.is_empty());
+                    break;
+                }
+            }
+        }
+        assert_eq!(queries, 7);
+    }
+
+    #[test]
+    fn should_fail_over_on_upward_referral() {
+        let server = Server::new();
+        let lame = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+        let ns = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 53);
+        let qname: Name = "www.example.com".parse().unwrap();
+        let mut resolution = Resolution::ask(
+            &qname,
+            QueryType::A,
+            "example.com".parse().unwrap(),
+            vec![lame, ns],
+            Instant::now() + Duration::from_secs(5),
+            Trace::off(),
+        );
+
+        // sends us back up to com, which is no help
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, lame);
+        let com_ns = Record::NS {
+            domain: "com".parse().unwrap(),
+            host: "a.gtld-servers.net".parse().unwrap(),
+            ttl: 300,
+        };
+        let mut res_packet = response_to(&send_packet, vec![], vec![com_ns]);
+        res_packet.additionals.push(Record::A {
+            domain: "a.gtld-servers.net".parse().unwrap(),
+            ip: Ipv4Addr::new(192, 5, 6, 30),
+            ttl: 300,
+        });
+        resolution.received(Ok((res_packet, 100)));
+
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, ns);
+        let www_a = Record::A {
+            domain: qname.clone(),
+            ip: Ipv4Addr::new(192, 0, 2, 80),
+            ttl: 300,
+        };
+        resolution.received(Ok((
+            response_to(&send_packet, vec![www_a.clone()], vec![]),
+            100,
+        )));
+
+        match resolution.poll(&server) {
+            Action::Done(result) => assert_eq!(result.unwrap().answers, [www_a]),
+            Action::Send { .. } => panic!("one query too many"),
+        }
+    }
+
+    #[test]
+    fn should_prime_before_starting_from_root() {
+        let root_hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut server = Server::new();
+        server.set_root_hints(vec![root_hint]);
+        let qname: Name = "google.com".parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut resolution = Resolution::new(&qname, QueryType::A, deadline, Trace::new());
+
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (root_hint, 53));
+        assert!(send_packet.questions[0].name.is_root());
+        let ns = Record::NS {
+            domain: Name::root(),
+            host: "a.root-servers.net".parse().unwrap(),
+            ttl: 518400,
+        };
+        let mut res_packet = response_to(&send_packet, vec![ns], vec![]);
+        res_packet.additionals.push(Record::A {
+            domain: "a.root-servers.net".parse().unwrap(),
+            ip: Ipv4Addr::new(198, 41, 0, 4),
+            ttl: 518400,
+        });
+        resolution.received(Ok((res_packet, 100)));
+
+        // on from the root servers priming found
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)), 53));
+        assert_eq!(send_packet.questions[0].name, qname);
+        assert!(!server.priming_due());
+    }
+
+    #[test]
+    fn should_not_retry_priming_right_away() {
+        let root_hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut server = Server::new();
+        server.set_root_hints(vec![root_hint]);
+        let qname: Name = "google.com".parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let mut resolution = Resolution::new(&qname, QueryType::A, deadline, Trace::off());
+        for _ in 0..=QUERY_RETRIES {
+            let (send_packet, _, _) = expect_send(resolution.poll(&server));
+            assert!(send_packet.questions[0].name.is_root());
+            resolution.received(Err(DnsError::Timeout));
+        }
+        // priming failed, on from the hints
+        let (send_packet, to, _) = expect_send(resolution.poll(&server));
+        assert_eq!(to, (root_hint, 53));
+        assert_eq!(send_packet.questions[0].name, qname);
+
+        // and the next lookup doesn't try again
+        let mut resolution = Resolution::new(&qname, QueryType::A, deadline, Trace::off());
+        let (send_packet, _, _) = expect_send(resolution.poll(&server));
+        assert_eq!(send_packet.questions[0].name, qname);
+    }
+}
@@ -1,122 +1,1491 @@
-use crate::dns::header::ResponseCode;
-use crate::dns::packet::{BytePacketBuffer, Packet};
+use crate::dns::cache::{Cache, DEFAULT_CACHE_SIZE};
+use crate::dns::error::DnsError;
+use crate::dns::header::{Header, ResponseCode};
+use crate::dns::name::Name;
+use crate::dns::packet::{BytePacketBuffer, Packet, MAX_EDNS_SIZE, MAX_TCP_SIZE, MAX_UDP_SIZE};
 use crate::dns::question::Question;
-use crate::dns::record::QueryType;
-use std::error::Error;
-use std::net::Ipv4Addr;
-use std::net::UdpSocket;
+use crate::dns::record::{Edns, QueryType, Record};
+use crate::dns::resolver::{Action, Resolution};
+use crate::dns::trace::{Hop, Trace};
+use crate::dns::zone::Zone;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct Server;
+/// How long a TCP connection may sit idle between queries before we close it.
+pub(crate) const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many CNAMEs we follow for one query before giving up.
+pub(crate) const MAX_CNAME_CHAIN: usize = 8;
+
+/// How long we wait for one upstream server to answer one query, by
+/// default.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many more times we go round a zone's servers after none of them
+/// answered.
+pub(crate) const QUERY_RETRIES: usize = 1;
+
+/// How long after one try at priming lookups wait before trying again, so
+/// that while the roots don't answer we don't ask them on every lookup.
+pub(crate) const PRIMING_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many recursive lookups may be under way at once by default.
+pub const DEFAULT_MAX_RECURSIONS: usize = 100;
+
+/// How long a client query may take all told before we give up with
+/// SERVFAIL, by default. Stub resolvers tend to stop waiting after about 5
+/// seconds.
+pub const DEFAULT_LOOKUP_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Where recursion starts when we're not told otherwise: the 13 root
+/// servers, a through m, as in IANA's `named.root`.
+pub const DEFAULT_ROOT_HINTS: [IpAddr; 26] = [
+    IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+    IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+    IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+    IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+    IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+    IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+    IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+    IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+    IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
+
+/// A recursive resolver, and an authoritative server for the zones added
+/// to it. Answers and delegations it learns along the way are cached, so
+/// one `Server` is meant to be shared by every handler.
+pub struct Server {
+    cache: Mutex<Cache>,
+    /// Lookups under way, for queries asking the same question to wait on.
+    inflight: Mutex<HashMap<(Name, QueryType), Arc<Inflight>>>,
+    recursions: Mutex<usize>,
+    recursion_done: Condvar,
+    pub(crate) max_recursions: usize,
+    root_hints: Vec<IpAddr>,
+    /// When priming was last tried, if ever.
+    last_priming: Mutex<Option<Instant>>,
+    pub(crate) query_timeout: Duration,
+    pub(crate) lookup_deadline: Duration,
+    zones: Vec<Zone>,
+}
+
+/// Where a response from one of a zone's servers leaves a lookup.
+pub(crate) enum Step {
+    /// That's the answer, or as close to one as we'll get.
+    Done(Packet),
+    /// The name is delegated to `zone`, whose servers are at `addrs` if the
+    /// response came with glue, or else named by `hosts`.
+    Referral {
+        zone: Name,
+        addrs: Vec<IpAddr>,
+        hosts: Vec<Name>,
+        res_packet: Packet,
+    },
+    /// The server referred us to where we already are, or back up the tree:
+    /// it's lame for the zone, we'd best ask another.
+    Lame,
+}
+
+/// The result of a lookup under way, once there is one.
+#[derive(Default)]
+struct Inflight {
+    result: Mutex<Option<Result<Packet, DnsError>>>,
+    done: Condvar,
+}
+
+/// Takes a lookup out of `inflight` when it's done, or unwinds half way,
+/// and wakes the queries waiting on it. If the lookup never got a result,
+/// they get a timeout.
+struct InflightGuard<'a> {
+    server: &'a Server,
+    key: (Name, QueryType),
+    inflight: Arc<Inflight>,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.server.inflight.lock().unwrap().remove(&self.key);
+        let mut result = self.inflight.result.lock().unwrap();
+        if result.is_none() {
+            *result = Some(Err(DnsError::Timeout));
+        }
+        self.inflight.done.notify_all();
+    }
+}
+
+/// One of the `max_recursions` slots, given back when dropped.
+struct RecursionSlot<'a> {
+    server: &'a Server,
+}
+
+impl Drop for RecursionSlot<'_> {
+    fn drop(&mut self) {
+        *self.server.recursions.lock().unwrap() -= 1;
+        self.server.recursion_done.notify_one();
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
 
 impl Server {
-    pub fn lookup(
-        domain: &str,
-        qtype: QueryType,
-        server: (Ipv4Addr, u16),
-    ) -> Result<Packet, Box<dyn Error>> {
-        let mut send_packet_buf = BytePacketBuffer::new();
+    pub fn new() -> Server {
+        Server::with_cache_size(DEFAULT_CACHE_SIZE)
+    }
+
+    /// A server whose cache holds at most `max_entries` RRsets.
+    pub fn with_cache_size(max_entries: usize) -> Server {
+        Server {
+            cache: Mutex::new(Cache::new(max_entries)),
+            inflight: Mutex::new(HashMap::new()),
+            recursions: Mutex::new(0),
+            recursion_done: Condvar::new(),
+            max_recursions: DEFAULT_MAX_RECURSIONS,
+            root_hints: DEFAULT_ROOT_HINTS.to_vec(),
+            last_priming: Mutex::new(None),
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            lookup_deadline: DEFAULT_LOOKUP_DEADLINE,
+            zones: Vec::new(),
+        }
+    }
+
+    /// Limits how many recursive lookups may be under way at once. Any more
+    /// wait for one to finish, and get SERVFAIL if that takes too long.
+    pub fn set_max_recursions(&mut self, max_recursions: usize) {
+        self.max_recursions = max_recursions;
+    }
+
+    /// Sets the root servers recursion starts from when the cache knows no
+    /// closer delegation, e.g. from `hints::load`.
+    pub fn set_root_hints(&mut self, root_hints: Vec<IpAddr>) {
+        self.root_hints = root_hints;
+    }
+
+    /// Sets how long we wait for one upstream server to answer one query.
+    pub fn set_query_timeout(&mut self, query_timeout: Duration) {
+        self.query_timeout = query_timeout;
+    }
+
+    /// Sets how long a client query may take all told before it gets
+    /// SERVFAIL.
+    pub fn set_lookup_deadline(&mut self, lookup_deadline: Duration) {
+        self.lookup_deadline = lookup_deadline;
+    }
+
+    /// Makes us authoritative for `zone`: questions in it get answered from
+    /// its data rather than by recursion.
+    pub fn add_zone(&mut self, zone: Zone) {
+        self.zones.retain(|e| e.origin() != zone.origin());
+        self.zones.push(zone);
+    }
+
+    /// The closest of our zones `qname` is in, if any.
+    pub(crate) fn zone_for(&self, qname: &Name) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|e| qname.is_subdomain_of(e.origin()))
+            .max_by_key(|e| e.origin().label_count())
+    }
+
+    /// A query for `domain` with a random ID, recursion desired and EDNS.
+    pub fn query_packet(domain: &Name, qtype: QueryType) -> Result<Packet, DnsError> {
         let mut send_packet = Packet::new();
-        send_packet.header.id = 1000;
+        send_packet.header.id = random_id()?;
         send_packet.header.query_response = false;
         send_packet.header.question_count = 1;
         send_packet.header.recursion_desired = true;
         send_packet
             .questions
-            .push(Question::new(domain.into(), qtype));
+            .push(Question::new(domain.clone(), qtype));
+        send_packet.set_edns(Edns::new(MAX_EDNS_SIZE as u16));
+        Ok(send_packet)
+    }
 
+    /// Sends one query over UDP from a random port and waits up to
+    /// `timeout` for the answer. Anything that isn't from `server` or
+    /// doesn't match the query's ID and question is dropped, it's most
+    /// likely someone trying to poison us.
+    pub fn lookup(
+        domain: &Name,
+        qtype: QueryType,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<Packet, DnsError> {
+        let send_packet = Server::query_packet(domain, qtype)?;
+        Server::exchange(&send_packet, server, timeout).map(|e| e.0)
+    }
+
+    pub fn lookup_tcp(
+        domain: &Name,
+        qtype: QueryType,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<Packet, DnsError> {
+        let send_packet = Server::query_packet(domain, qtype)?;
+        Server::exchange_tcp(&send_packet, server, timeout).map(|e| e.0)
+    }
+
+    /// Sends `send_packet` over UDP like `lookup`, giving back the response
+    /// and its size in bytes.
+    pub fn exchange(
+        send_packet: &Packet,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<(Packet, usize), DnsError> {
+        let mut send_packet_buf = BytePacketBuffer::new();
         send_packet.write(&mut send_packet_buf)?;
 
-        let udp_socket = UdpSocket::bind(("0.0.0.0", 40053))?;
-        udp_socket.send_to(&send_packet_buf.buf, server)?;
+        // port 0 has the OS pick an ephemeral port for us
+        let local = match server.0 {
+            IpAddr::V4(_) => "0.0.0.0",
+            IpAddr::V6(_) => "::",
+        };
+        let udp_socket = UdpSocket::bind((local, 0))?;
+        let len = send_packet_buf.pos();
+        udp_socket.send_to(send_packet_buf.read_range(0, len)?, server)?;
 
-        let mut rev_packet_buf = BytePacketBuffer::new();
-        udp_socket.recv_from(&mut rev_packet_buf.buf)?;
+        let deadline = Instant::now() + timeout;
+        let mut rev_data = [0; MAX_EDNS_SIZE];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DnsError::Timeout);
+            }
+            udp_socket.set_read_timeout(Some(deadline - now))?;
 
-        let rev_packet = Packet::from_buf(&mut rev_packet_buf)?;
+            let (len, src) = udp_socket.recv_from(&mut rev_data)?;
+            if src != SocketAddr::from(server) {
+                debug!("dropping response from unexpected source {}", src);
+                continue;
+            }
 
-        Ok(rev_packet)
+            match parse_response(&rev_data[..len], send_packet) {
+                Ok(rev_packet) => return Ok((rev_packet, len)),
+                Err(DnsError::UnexpectedResponse) => {
+                    debug!("dropping response that doesn't match the query")
+                }
+                Err(e) => debug!("dropping malformed response: {}", e),
+            }
+        }
     }
 
-    pub fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<Packet, Box<dyn Error>> {
-        let mut ns = "198.41.0.4".parse::<Ipv4Addr>()?;
+    /// Sends `send_packet` over TCP, giving back the response and its size
+    /// in bytes.
+    pub fn exchange_tcp(
+        send_packet: &Packet,
+        server: (IpAddr, u16),
+        timeout: Duration,
+    ) -> Result<(Packet, usize), DnsError> {
+        let send_data = encode(send_packet, MAX_TCP_SIZE)?;
 
-        loop {
-            println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+        let mut stream = TcpStream::connect_timeout(&SocketAddr::from(server), timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write_tcp_message(&mut stream, &send_data)?;
+
+        let rev_data = read_tcp_message(&mut stream)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")
+        })?;
+        let rev_packet = parse_response(&rev_data, send_packet)?;
 
-            let ns_copy = ns;
-            let server = (ns_copy, 53);
-            let res_packet = Server::lookup(qname, qtype, server)?;
+        Ok((rev_packet, rev_data.len()))
+    }
 
-            if !res_packet.answers.is_empty()
-                && res_packet.header.response_code == ResponseCode::NOERROR
-            {
-                return Ok(res_packet);
+    /// Resolves `qname`, following CNAMEs to where they lead. The answers
+    /// hold the chain of CNAMEs in order, then the RRset at the end of it.
+    ///
+    /// Concurrent calls asking the same question share one lookup.
+    pub fn recursive_lookup(&self, qname: &Name, qtype: QueryType) -> Result<Packet, DnsError> {
+        let key = (qname.clone(), qtype);
+        let (inflight, leader) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(e) => (e.clone(), false),
+                None => {
+                    let e = Arc::new(Inflight::default());
+                    inflight.insert(key.clone(), e.clone());
+                    (e, true)
+                }
             }
+        };
 
-            if res_packet.header.response_code == ResponseCode::NXDOMAIN {
-                return Ok(res_packet);
+        if !leader {
+            let mut result = inflight.result.lock().unwrap();
+            loop {
+                match result.as_ref() {
+                    Some(result) => return result.clone(),
+                    None => result = inflight.done.wait(result).unwrap(),
+                }
             }
+        }
 
-            if let Some(ns_new) = res_packet.resolved_ns(qname) {
-                ns = ns_new;
-                continue;
+        let _guard = InflightGuard {
+            server: self,
+            key,
+            inflight: inflight.clone(),
+        };
+
+        let deadline = Instant::now() + self.lookup_deadline;
+        let result = self.recursion_slot(deadline).and_then(|_slot| {
+            self.run(Resolution::new(qname, qtype, deadline, Trace::off()))
+                .0
+        });
+        *inflight.result.lock().unwrap() = Some(result.clone());
+
+        result
+    }
+
+    /// Resolves `qname` like `recursive_lookup`, on its own rather than
+    /// sharing a lookup under way, and gives each hop it took along with
+    /// the result.
+    pub fn trace_lookup(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> (Result<Packet, DnsError>, Vec<Hop>) {
+        let deadline = Instant::now() + self.lookup_deadline;
+        match self.recursion_slot(deadline) {
+            Ok(_slot) => self.run(Resolution::new(qname, qtype, deadline, Trace::new())),
+            Err(e) => (Err(e), Vec::new()),
+        }
+    }
+
+    /// Waits for one of the `max_recursions` slots to be free, until
+    /// `deadline`.
+    fn recursion_slot(&self, deadline: Instant) -> Result<RecursionSlot<'_>, DnsError> {
+        let mut recursions = self.recursions.lock().unwrap();
+        while *recursions >= self.max_recursions {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DnsError::Timeout);
+            }
+            recursions = self
+                .recursion_done
+                .wait_timeout(recursions, deadline - now)
+                .unwrap()
+                .0;
+        }
+        *recursions += 1;
+
+        Ok(RecursionSlot { server: self })
+    }
+
+    /// Sends the queries `resolution` asks for until it's done, giving its
+    /// result and the hops it took.
+    fn run(&self, mut resolution: Resolution) -> (Result<Packet, DnsError>, Vec<Hop>) {
+        loop {
+            match resolution.poll(self) {
+                Action::Send {
+                    send_packet,
+                    server,
+                    tcp,
+                    timeout,
+                } => {
+                    let response = if tcp {
+                        Server::exchange_tcp(&send_packet, server, timeout)
+                    } else {
+                        Server::exchange(&send_packet, server, timeout)
+                    };
+                    resolution.received(response);
+                }
+                Action::Done(result) => return (result, resolution.into_hops()),
             }
+        }
+    }
 
-            let qname_new = match res_packet.unresolved_ns(qname) {
-                Some(v) => v,
-                _ => return Ok(res_packet),
+    /// Follows the CNAMEs in `res_packet`, the response for `name`, adding
+    /// them to `chain`. Gives the final response, or `None` if the chain
+    /// leads out of the response and resolution has to start over at the
+    /// new `name`.
+    pub(crate) fn chase(
+        qname: &Name,
+        qtype: QueryType,
+        chain: &mut Vec<Record>,
+        name: &mut Name,
+        mut res_packet: Packet,
+    ) -> Option<Packet> {
+        let answers = std::mem::take(&mut res_packet.answers);
+        let resolved = name.clone();
+
+        // the answer may already hold part of the chain, or all of it
+        while qtype != QueryType::CNAME
+            && !answers
+                .iter()
+                .any(|e| e.qtype() == qtype && e.domain() == Some(&*name))
+        {
+            let cname = answers.iter().find(|e| match e {
+                Record::CNAME { domain, .. } => domain == name,
+                _ => false,
+            });
+            let host = match cname {
+                Some(Record::CNAME { host, .. }) => host,
+                _ => break,
             };
 
-            let recursive_pakcet = Server::recursive_lookup(qname_new, QueryType::A)?;
-            if let Some(v) = recursive_pakcet.random_answer() {
-                ns = v;
-                continue;
+            let is_loop = host == qname || chain.iter().any(|e| e.domain() == Some(host));
+            chain.push(cname.unwrap().clone());
+            if is_loop || chain.len() > MAX_CNAME_CHAIN {
+                warn!("giving up on CNAME chain at {}", host);
+                let mut res_packet = Packet::new();
+                res_packet.header.query_response = true;
+                res_packet.header.response_code = ResponseCode::SERVFAIL;
+                res_packet.answers = std::mem::take(chain);
+                return Some(res_packet);
             }
 
-            return Ok(res_packet);
+            *name = host.clone();
+        }
+
+        let complete = answers
+            .iter()
+            .any(|e| e.domain() == Some(&*name) && e.qtype() == qtype);
+        if *name != resolved && !complete {
+            // start over at the target, its zone may be somewhere else
+            return None;
         }
+
+        res_packet.answers = std::mem::take(chain);
+        res_packet.answers.extend(
+            answers
+                .into_iter()
+                .filter(|e| e.domain() == Some(&*name) && e.qtype() == qtype),
+        );
+        Some(res_packet)
     }
 
-    pub fn handle_query(socket: &UdpSocket) -> Result<(), Box<dyn Error>> {
-        let mut req_packet_buf = BytePacketBuffer::new();
-        let (_, src) = socket.recv_from(&mut req_packet_buf.buf)?;
-        let mut req_packet = Packet::from_buf(&mut req_packet_buf)?;
+    /// Asks the root hints for the current set of root servers (RFC 8109
+    /// priming) and caches it, so lookups start from that rather than the
+    /// hints. Lookups do this themselves whenever the set is missing from
+    /// the cache or expired, so calling it up front only spares the first
+    /// of them the wait.
+    pub fn prime(&self) -> Result<(), DnsError> {
+        *self.last_priming.lock().unwrap() = Some(Instant::now());
+        let deadline = Instant::now() + self.lookup_deadline;
+        let result = self.run(Resolution::prime(self, deadline, Trace::off())).0;
+        self.check_priming(result)
+    }
 
+    /// Whether a lookup about to start from the root should prime first:
+    /// there's no primed set of root servers in the cache, and nobody has
+    /// tried priming in the last `PRIMING_RETRY_INTERVAL`. Only says yes to
+    /// one caller, who is then expected to try.
+    pub(crate) fn priming_due(&self) -> bool {
+        if self.primed_root_servers().is_some() {
+            return false;
+        }
+        let mut last_priming = self.last_priming.lock().unwrap();
+        let now = Instant::now();
+        if last_priming.is_some_and(|e| now < e + PRIMING_RETRY_INTERVAL) {
+            return false;
+        }
+        *last_priming = Some(now);
+        true
+    }
+
+    /// Checks what a priming query came to. The response itself was
+    /// cached on the way in.
+    pub(crate) fn check_priming(&self, result: Result<Packet, DnsError>) -> Result<(), DnsError> {
+        let root = Name::root();
+        let res_packet = result?;
+
+        let has_ns = res_packet
+            .answers
+            .iter()
+            .any(|e| e.qtype() == QueryType::NS && e.domain() == Some(&root));
+        if res_packet.header.response_code != ResponseCode::NOERROR || !has_ns {
+            return Err(DnsError::UnexpectedResponse);
+        }
+
+        match self.primed_root_servers() {
+            Some(_) => Ok(()),
+            // NS records without glue, no better than the hints
+            None => Err(DnsError::UnexpectedResponse),
+        }
+    }
+
+    /// The closest zone we already know servers for, and their addresses.
+    pub(crate) fn start_zone(&self, qname: &Name) -> (Name, Vec<IpAddr>) {
+        let delegation = self.cache.lock().unwrap().delegation(qname, Instant::now());
+        match delegation {
+            Some((zone, addrs)) if !zone.is_root() => (zone, addrs),
+            _ => (Name::root(), self.root_servers()),
+        }
+    }
+
+    /// The root servers from priming, or else the hints, starting at a
+    /// random one so we spread our queries over all of them.
+    pub(crate) fn root_servers(&self) -> Vec<IpAddr> {
+        let mut addrs = self
+            .primed_root_servers()
+            .unwrap_or_else(|| self.root_hints.clone());
+        if !addrs.is_empty() {
+            let start = random_id().unwrap_or_default() as usize % addrs.len();
+            addrs.rotate_left(start);
+        }
+        addrs
+    }
+
+    /// The root servers from priming, while they're in the cache.
+    fn primed_root_servers(&self) -> Option<Vec<IpAddr>> {
+        let root = Name::root();
+        match self.cache.lock().unwrap().delegation(&root, Instant::now()) {
+            Some((zone, addrs)) if zone.is_root() => Some(addrs),
+            _ => None,
+        }
+    }
+
+    /// Caches `res_packet`, the response for `qname` from a server for
+    /// `zone`, and works out where it leaves the lookup.
+    pub(crate) fn next_step(
+        &self,
+        mut res_packet: Packet,
+        qname: &Name,
+        qtype: QueryType,
+        zone: &Name,
+    ) -> Step {
+        self.cache_response(&res_packet, zone);
+        // answers from outside the zone are none of this server's
+        // business, we'll chase CNAMEs out of it ourselves
+        res_packet.answers.retain(|e| {
+            e.domain()
+                .is_some_and(|domain| domain.is_subdomain_of(zone))
+        });
+
+        if !res_packet.answers.is_empty()
+            && res_packet.header.response_code == ResponseCode::NOERROR
+        {
+            return Step::Done(res_packet);
+        }
+
+        if res_packet.header.response_code == ResponseCode::NXDOMAIN {
+            // with a CNAME in the answers it's the target that's missing
+            if res_packet.answers.is_empty() {
+                self.cache_negative(&res_packet, qname, None, zone);
+            }
+            return Step::Done(res_packet);
+        }
+
+        // NODATA, the name exists but has nothing of this type
+        if res_packet.header.response_code == ResponseCode::NOERROR
+            && res_packet.answers.is_empty()
+            && res_packet.soa().is_some()
+        {
+            self.cache_negative(&res_packet, qname, Some(qtype), zone);
+            return Step::Done(res_packet);
+        }
+
+        let zone_new = match res_packet.referral_zone(qname) {
+            Some(v) => v.clone(),
+            _ => return Step::Done(res_packet),
+        };
+        if zone_new == *zone || !zone_new.is_subdomain_of(zone) {
+            return Step::Lame;
+        }
+
+        Step::Referral {
+            zone: zone_new,
+            addrs: res_packet.resolved_ns_addrs(qname),
+            hosts: res_packet.ns_hosts(qname).into_iter().cloned().collect(),
+            res_packet,
+        }
+    }
+
+    /// Builds the response to `qname` and `qtype` from the cache, positive
+    /// or negative, if it's there.
+    pub(crate) fn cached_response(&self, qname: &Name, qtype: QueryType) -> Option<Packet> {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+
+        let mut res_packet = Packet::new();
+        res_packet.header.query_response = true;
+        if let Some(answers) = cache.lookup(qname, qtype, now) {
+            res_packet.answers = answers;
+        } else if let Some(cname) = cache.lookup(qname, QueryType::CNAME, now) {
+            res_packet.answers = cname;
+        } else if let Some((rcode, soa)) = cache.lookup_negative(qname, qtype, now) {
+            res_packet.header.response_code = rcode;
+            res_packet.authorities.push(soa);
+        } else {
+            return None;
+        }
+
+        Some(res_packet)
+    }
+
+    /// Remembers that `qname` doesn't exist, or has no `qtype`, going by the
+    /// SOA in a response from a server for `zone`.
+    fn cache_negative(
+        &self,
+        res_packet: &Packet,
+        qname: &Name,
+        qtype: Option<QueryType>,
+        zone: &Name,
+    ) {
+        let soa = match res_packet.soa() {
+            Some(soa) => soa,
+            None => return,
+        };
+        let in_bailiwick = soa
+            .domain()
+            .is_some_and(|owner| owner.is_subdomain_of(zone) && qname.is_subdomain_of(owner));
+        if in_bailiwick {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert_negative(qname, qtype, soa, Instant::now());
+        }
+    }
+
+    /// Caches the records in a response from a server for `zone`. Anything
+    /// outside the zone is left out, that server has no say over it.
+    fn cache_response(&self, res_packet: &Packet, zone: &Name) {
+        let records: Vec<Record> = res_packet
+            .answers
+            .iter()
+            .chain(res_packet.authorities.iter())
+            .chain(res_packet.additionals.iter())
+            .filter(|e| {
+                e.domain()
+                    .is_some_and(|domain| domain.is_subdomain_of(zone))
+            })
+            .cloned()
+            .collect();
+        self.cache.lock().unwrap().insert(&records, Instant::now());
+    }
+
+    /// The cached A and AAAA records for each of `hosts`.
+    fn cached_addresses(&self, hosts: &[&Name]) -> Vec<Record> {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        let mut addrs = Vec::new();
+        for host in hosts.iter() {
+            for qtype in [QueryType::A, QueryType::AAAA].iter() {
+                addrs.extend(cache.lookup(host, *qtype, now).unwrap_or_default());
+            }
+        }
+        addrs
+    }
+
+    /// The question in `req_packet` that needs a lookup, if any. Requests
+    /// without one, or with an EDNS version we don't speak, get an answer
+    /// without.
+    pub(crate) fn question_to_resolve(req_packet: &Packet) -> Option<&Question> {
+        if req_packet.edns().is_some_and(|e| e.version > 0) {
+            return None;
+        }
+        req_packet.questions.last()
+    }
+
+    fn respond(&self, req_packet: Packet) -> Packet {
+        let lookup = Server::question_to_resolve(&req_packet).map(|question| {
+            match self.zone_for(&question.name) {
+                Some(zone) => Ok(zone.lookup(&question.name, question.qtype)),
+                None => self.recursive_lookup(&question.name, question.qtype),
+            }
+        });
+        self.build_response(req_packet, lookup)
+    }
+
+    /// Builds the response to `req_packet` out of `lookup`, the result of
+    /// looking up its `question_to_resolve`.
+    pub(crate) fn build_response(
+        &self,
+        mut req_packet: Packet,
+        lookup: Option<Result<Packet, DnsError>>,
+    ) -> Packet {
         let mut res_packet = Packet::new();
         res_packet.header.id = req_packet.header.id;
         res_packet.header.recursion_desired = true;
         res_packet.header.recursion_available = true;
         res_packet.header.query_response = true;
 
-        if let Some(question) = req_packet.questions.pop() {
-            println!("Question: {:?}", question);
+        let req_edns = req_packet.edns().cloned();
+        let mut res_edns = Edns::new(MAX_EDNS_SIZE as u16);
 
-            if let Ok(rev_packet) = Server::recursive_lookup(&question.name, question.qtype) {
-                res_packet.questions.push(question);
-                for rec in rev_packet.answers {
-                    println!("Answers: {:#?}", rec);
-                    res_packet.answers.push(rec);
-                }
-                for rec in rev_packet.authorities {
-                    println!("authorities: {:#?}", rec);
-                    res_packet.authorities.push(rec);
+        if req_edns.as_ref().is_some_and(|e| e.version > 0) {
+            // BADVERS, we only speak EDNS version 0
+            res_edns.extended_rcode = 1;
+        } else if let (Some(question), Some(lookup)) = (req_packet.questions.pop(), lookup) {
+            debug!("question: {}", question);
+
+            match lookup {
+                Ok(rev_packet) => {
+                    res_packet.header.response_code = rev_packet.header.response_code;
+                    // only answers from our own zones are ours to vouch for,
+                    // not ones we got from upstream
+                    res_packet.header.authoritative_answer = rev_packet.header.authoritative_answer
+                        && self.zone_for(&question.name).is_some();
+                    for rec in rev_packet.answers {
+                        debug!("answer: {}", rec);
+                        res_packet.answers.push(rec);
+                    }
+                    for rec in rev_packet.authorities {
+                        debug!("authority: {}", rec);
+                        res_packet.authorities.push(rec);
+                    }
+                    let mut known = rev_packet.additionals;
+                    known.extend(self.cached_addresses(&res_packet.additional_hosts()));
+                    Server::add_additionals(&mut res_packet, &known);
+                    for rec in res_packet.additionals.iter() {
+                        debug!("additional: {}", rec);
+                    }
                 }
-                for rec in rev_packet.additionals {
-                    println!("additionals: {:#?}", rec);
-                    res_packet.additionals.push(rec);
+                Err(e) => {
+                    warn!("lookup err: {}", e);
+                    res_packet.header.response_code = ResponseCode::SERVFAIL;
                 }
             }
+            res_packet.questions.push(question);
         } else {
             res_packet.header.response_code = ResponseCode::SERVFAIL;
         }
 
-        let mut res_packet_buf = BytePacketBuffer::new();
-        res_packet.write(&mut res_packet_buf)?;
+        if req_edns.is_some() {
+            res_packet.set_edns(res_edns);
+        }
+
+        res_packet
+    }
+
+    /// Additional section processing: adds the addresses we know among
+    /// `known` for every MX exchange and NS host in the response. Anything
+    /// else the upstream put in its additional section is left out.
+    fn add_additionals(res_packet: &mut Packet, known: &[Record]) {
+        let hosts: Vec<Name> = res_packet.additional_hosts().into_iter().cloned().collect();
+        for host in hosts.iter() {
+            for rec in known.iter() {
+                match rec {
+                    Record::A { domain, .. } | Record::AAAA { domain, .. }
+                        if domain == host && !res_packet.additionals.contains(rec) =>
+                    {
+                        res_packet.additionals.push(rec.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Builds the reply to a request we failed to parse: FORMERR if the
+    /// request itself is malformed, SERVFAIL otherwise. `None` if there
+    /// isn't even a header to reply to.
+    pub(crate) fn error_response(
+        req_packet_buf: &mut BytePacketBuffer,
+        e: &DnsError,
+    ) -> Option<Packet> {
+        let mut req_header = Header::new();
+        req_header.read(req_packet_buf).ok()?;
+
+        let mut res_packet = Packet::new();
+        res_packet.header.id = req_header.id;
+        res_packet.header.opcode = req_header.opcode;
+        res_packet.header.recursion_desired = req_header.recursion_desired;
+        res_packet.header.recursion_available = true;
+        res_packet.header.query_response = true;
+        res_packet.header.response_code = if e.is_format_error() {
+            ResponseCode::FORMERR
+        } else {
+            ResponseCode::SERVFAIL
+        };
+
+        Some(res_packet)
+    }
+
+    pub fn handle_query(&self, socket: &UdpSocket) -> Result<(), DnsError> {
+        let mut req_data = [0; MAX_EDNS_SIZE];
+        let (len, src) = socket.recv_from(&mut req_data)?;
+        self.handle_datagram(socket, &req_data[..len], src)
+    }
 
-        let len = res_packet_buf.pos();
-        let data = res_packet_buf.read_range(0, len)?;
-        socket.send_to(data, src)?;
+    /// Answers the query in `req_data`, received on `socket` from `src`.
+    pub fn handle_datagram(
+        &self,
+        socket: &UdpSocket,
+        req_data: &[u8],
+        src: SocketAddr,
+    ) -> Result<(), DnsError> {
+        let req_packet = match parse_request(req_data) {
+            Request::Query(packet) => packet,
+            Request::Malformed(e, res_packet) => {
+                if let Some(res_packet) = res_packet {
+                    socket.send_to(&encode(&res_packet, MAX_UDP_SIZE)?, src)?;
+                }
+                return Err(e);
+            }
+        };
+
+        let res_size = udp_response_size(&req_packet);
+        let res_packet = self.respond(req_packet);
+        socket.send_to(&encode(&res_packet, res_size)?, src)?;
 
         Ok(())
     }
+
+    /// Answers length-prefixed queries on `stream` until the client closes
+    /// the connection or stays idle for longer than `TCP_IDLE_TIMEOUT`.
+    pub fn handle_tcp_connection(&self, mut stream: TcpStream) -> Result<(), DnsError> {
+        stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+
+        loop {
+            let req_data = match read_tcp_message(&mut stream) {
+                Ok(Some(data)) => data,
+                Ok(None) => return Ok(()),
+                Err(e) => match DnsError::from(e) {
+                    DnsError::Timeout => return Ok(()),
+                    e => return Err(e),
+                },
+            };
+
+            let res_packet = match parse_request(&req_data) {
+                Request::Query(req_packet) => self.respond(req_packet),
+                Request::Malformed(_, Some(res_packet)) => res_packet,
+                Request::Malformed(e, None) => return Err(e),
+            };
+
+            write_tcp_message(&mut stream, &encode(&res_packet, MAX_TCP_SIZE)?)?;
+        }
+    }
+}
+
+/// A request off the wire.
+pub(crate) enum Request {
+    Query(Packet),
+    /// One we couldn't parse, and the reply to send for it, if any.
+    Malformed(DnsError, Option<Packet>),
+}
+
+pub(crate) fn parse_request(req_data: &[u8]) -> Request {
+    let mut req_packet_buf = BytePacketBuffer::from_bytes(req_data);
+    match Packet::from_buf(&mut req_packet_buf) {
+        Ok(req_packet) => Request::Query(req_packet),
+        Err(e) => {
+            let res_packet = Server::error_response(&mut req_packet_buf, &e);
+            Request::Malformed(e, res_packet)
+        }
+    }
+}
+
+/// Reads `res_data` as the response to `req_packet`. Anything else, even if
+/// it parses, is a `DnsError::UnexpectedResponse`.
+pub(crate) fn parse_response(res_data: &[u8], req_packet: &Packet) -> Result<Packet, DnsError> {
+    let res_packet = Packet::from_buf(&mut BytePacketBuffer::from_bytes(res_data))?;
+    if !is_response_to(&res_packet, req_packet) {
+        return Err(DnsError::UnexpectedResponse);
+    }
+
+    Ok(res_packet)
+}
+
+/// How big a UDP response to `req_packet` may be: what the client says it
+/// can take over EDNS, within reason, or 512 bytes without EDNS.
+pub(crate) fn udp_response_size(req_packet: &Packet) -> usize {
+    req_packet.edns().map_or(MAX_UDP_SIZE, |e| {
+        (e.udp_payload_size as usize).clamp(MAX_UDP_SIZE, MAX_EDNS_SIZE)
+    })
+}
+
+/// The wire form of `packet`, truncated to fit `max_size` bytes.
+pub(crate) fn encode(packet: &Packet, max_size: usize) -> Result<Vec<u8>, DnsError> {
+    let mut packet_buf = BytePacketBuffer::with_max_size(max_size);
+    packet.write(&mut packet_buf)?;
+    let len = packet_buf.pos();
+    Ok(packet_buf.read_range(0, len)?.to_vec())
+}
+
+/// A fresh transaction ID, unpredictable so that nobody off the path can
+/// forge a response to our query.
+pub(crate) fn random_id() -> Result<u16, DnsError> {
+    let mut buf = [0; 2];
+    getrandom::getrandom(&mut buf).map_err(io::Error::from)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+/// Whether `res_packet` answers `req_packet`: a response with the same ID
+/// and the same question.
+pub(crate) fn is_response_to(res_packet: &Packet, req_packet: &Packet) -> bool {
+    res_packet.header.query_response
+        && res_packet.header.id == req_packet.header.id
+        && res_packet.questions.len() == req_packet.questions.len()
+        && res_packet
+            .questions
+            .iter()
+            .zip(req_packet.questions.iter())
+            .all(|(a, b)| a.name == b.name && a.qtype == b.qtype)
+}
+
+/// `data` framed for TCP: its length in two bytes, then the data.
+pub(crate) fn frame_tcp_message(data: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(data.len() + 2);
+    framed.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    framed.extend_from_slice(data);
+    framed
+}
+
+/// A buffer for the TCP message whose length prefix is `len_buf`.
+pub(crate) fn tcp_message_buf(len_buf: [u8; 2]) -> Vec<u8> {
+    vec![0; u16::from_be_bytes(len_buf) as usize]
+}
+
+/// Reads one message framed by a two-byte length prefix, or `None` if the
+/// peer closed the connection before starting a new message.
+fn read_tcp_message<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 2];
+    match stream.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut data = tcp_message_buf(len_buf);
+    stream.read_exact(&mut data)?;
+
+    Ok(Some(data))
+}
+
+fn write_tcp_message<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&frame_tcp_message(data))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn should_frame_tcp_messages() {
+        let mut stream = Vec::new();
+        write_tcp_message(&mut stream, &[0x8a, 0x5b, 0x01]).unwrap();
+        write_tcp_message(&mut stream, &[0x02]).unwrap();
+
+        assert_eq!(stream, [0x00, 0x03, 0x8a, 0x5b, 0x01, 0x00, 0x01, 0x02]);
+
+        let mut cursor = Cursor::new(stream);
+        assert_eq!(
+            read_tcp_message(&mut cursor).unwrap(),
+            Some(vec![0x8a, 0x5b, 0x01])
+        );
+        assert_eq!(read_tcp_message(&mut cursor).unwrap(), Some(vec![0x02]));
+        assert_eq!(read_tcp_message(&mut cursor).unwrap(), None);
+    }
+
+    #[test]
+    fn should_answer_pipelined_tcp_queries() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Server::new().handle_tcp_connection(stream).unwrap();
+        });
+
+        // queries without a question are answered locally with SERVFAIL
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for id in [1u16, 2] {
+            let mut req_packet = Packet::new();
+            req_packet.header.id = id;
+            let mut req_packet_buf = BytePacketBuffer::new();
+            req_packet.write(&mut req_packet_buf).unwrap();
+            let len = req_packet_buf.pos();
+            write_tcp_message(&mut stream, req_packet_buf.read_range(0, len).unwrap()).unwrap();
+        }
+
+        for id in [1u16, 2] {
+            let data = read_tcp_message(&mut stream).unwrap().unwrap();
+            let res_packet = Packet::from_buf(&mut BytePacketBuffer::from_bytes(&data)).unwrap();
+            assert_eq!(res_packet.header.id, id);
+            assert_eq!(res_packet.header.response_code, ResponseCode::SERVFAIL);
+        }
+
+        drop(stream);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn should_answer_malformed_query_with_formerr() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Server::new().handle_tcp_connection(stream)
+        });
+
+        // one question announced, but its name runs off the end
+        let req_data = [
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x67,
+        ];
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_tcp_message(&mut stream, &req_data).unwrap();
+
+        let data = read_tcp_message(&mut stream).unwrap().unwrap();
+        let res_packet = Packet::from_buf(&mut BytePacketBuffer::from_bytes(&data)).unwrap();
+        assert_eq!(res_packet.header.id, 0x1234);
+        assert_eq!(res_packet.header.response_code, ResponseCode::FORMERR);
+
+        drop(stream);
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn should_answer_from_cache() {
+        let server = Server::new();
+        let rec = Record::A {
+            domain: "google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(142, 250, 4, 100),
+            ttl: 300,
+        };
+        server
+            .cache
+            .lock()
+            .unwrap()
+            .insert(std::slice::from_ref(&rec), Instant::now());
+
+        let res_packet = server
+            .recursive_lookup(&"google.com".parse().unwrap(), QueryType::A)
+            .unwrap();
+
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(res_packet.answers, [rec]);
+
+        let (result, hops) = server.trace_lookup(&"google.com".parse().unwrap(), QueryType::A);
+        assert_eq!(result.unwrap().answers, res_packet.answers);
+        assert_eq!(hops.len(), 1);
+        assert_eq!(hops[0].to_string(), "google.com. A from cache");
+    }
+
+    #[test]
+    fn should_start_from_primed_root_servers() {
+        let server = Server::new();
+        let qname: Name = "google.com".parse().unwrap();
+
+        let (zone, mut addrs) = server.start_zone(&qname);
+        assert!(zone.is_root());
+        addrs.sort();
+        let mut hints = DEFAULT_ROOT_HINTS.to_vec();
+        hints.sort();
+        assert_eq!(addrs, hints);
+
+        let mut res_packet = Packet::new();
+        res_packet.answers.push(Record::NS {
+            domain: Name::root(),
+            host: "a.root-servers.net".parse().unwrap(),
+            ttl: 518400,
+        });
+        res_packet.additionals.push(Record::A {
+            domain: "a.root-servers.net".parse().unwrap(),
+            ip: Ipv4Addr::new(198, 41, 0, 4),
+            ttl: 518400,
+        });
+        server.cache_response(&res_packet, &Name::root());
+
+        let (zone, addrs) = server.start_zone(&qname);
+        assert!(zone.is_root());
+        assert_eq!(addrs, [IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4))]);
+    }
+
+    #[test]
+    fn should_keep_hints_when_priming_fails() {
+        // TEST-NET-1, nothing there answers
+        let hint = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let mut server = Server::new();
+        server.set_root_hints(vec![hint]);
+        server.set_lookup_deadline(Duration::from_millis(200));
+
+        assert!(server.prime().is_err());
+        assert_eq!(server.start_zone(&"google.com".parse().unwrap()).1, [hint]);
+    }
+
+    fn cname(domain: &str, host: &str) -> Record {
+        Record::CNAME {
+            domain: domain.parse().unwrap(),
+            host: host.parse().unwrap(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn should_fail_over_to_next_server() {
+        // takes our query and never answers
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut req_data = [0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut req_data).unwrap();
+            let req_packet =
+                Packet::from_buf(&mut BytePacketBuffer::from_bytes(&req_data[..len])).unwrap();
+
+            let mut res_packet = Packet::new();
+            res_packet.header.id = req_packet.header.id;
+            res_packet.header.query_response = true;
+            res_packet.questions = req_packet.questions;
+            res_packet.answers.push(Record::A {
+                domain: "google.com".parse().unwrap(),
+                ip: Ipv4Addr::new(142, 250, 4, 100),
+                ttl: 300,
+            });
+            let mut res_packet_buf = BytePacketBuffer::new();
+            res_packet.write(&mut res_packet_buf).unwrap();
+            let len = res_packet_buf.pos();
+            socket
+                .send_to(res_packet_buf.read_range(0, len).unwrap(), src)
+                .unwrap();
+        });
+
+        let servers = [
+            (silent_addr.ip(), silent_addr.port()),
+            (addr.ip(), addr.port()),
+        ];
+        let mut server = Server::new();
+        server.set_query_timeout(Duration::from_millis(200));
+        let (result, hops) = server.run(Resolution::ask(
+            &"google.com".parse().unwrap(),
+            QueryType::A,
+            "com".parse().unwrap(),
+            servers.to_vec(),
+            Instant::now() + DEFAULT_LOOKUP_DEADLINE,
+            Trace::new(),
+        ));
+
+        assert_eq!(
+            result.unwrap().random_answer(),
+            Some(IpAddr::V4(Ipv4Addr::new(142, 250, 4, 100)))
+        );
+        assert_eq!(hops.len(), 2);
+        match &hops[0] {
+            Hop::Query {
+                server,
+                rtt,
+                response,
+                ..
+            } => {
+                assert_eq!(*server, silent_addr.ip());
+                assert!(*rtt >= Duration::from_millis(200));
+                assert_eq!(response.as_ref().unwrap_err(), &DnsError::Timeout);
+            }
+            hop => panic!("unexpected hop {}", hop),
+        }
+        match &hops[1] {
+            Hop::Query { zone, response, .. } => {
+                assert_eq!(*zone, "com");
+                assert!(response.as_ref().unwrap().1 > 0);
+            }
+            hop => panic!("unexpected hop {}", hop),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn should_drop_spoofed_responses() {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut req_data = [0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut req_data).unwrap();
+            let req_packet =
+                Packet::from_buf(&mut BytePacketBuffer::from_bytes(&req_data[..len])).unwrap();
+
+            let send = |socket: &UdpSocket, id: u16, last: u8| {
+                let mut res_packet = Packet::new();
+                res_packet.header.id = id;
+                res_packet.header.query_response = true;
+                res_packet.questions = vec![Question::new(
+                    req_packet.questions[0].name.clone(),
+                    req_packet.questions[0].qtype,
+                )];
+                res_packet.answers.push(Record::A {
+                    domain: "google.com".parse().unwrap(),
+                    ip: Ipv4Addr::new(10, 0, 0, last),
+                    ttl: 300,
+                });
+                let mut res_packet_buf = BytePacketBuffer::new();
+                res_packet.write(&mut res_packet_buf).unwrap();
+                let len = res_packet_buf.pos();
+                socket
+                    .send_to(res_packet_buf.read_range(0, len).unwrap(), src)
+                    .unwrap();
+            };
+
+            let id = req_packet.header.id;
+            send(&socket, id.wrapping_add(1), 1);
+            send(&UdpSocket::bind(("127.0.0.1", 0)).unwrap(), id, 2);
+            send(&socket, id, 3);
+        });
+
+        let res_packet = Server::lookup(
+            &"google.com".parse().unwrap(),
+            QueryType::A,
+            (addr.ip(), addr.port()),
+            DEFAULT_QUERY_TIMEOUT,
+        )
+        .unwrap();
+
+        assert_eq!(
+            res_packet.random_answer(),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)))
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn should_time_out_after_deadline() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let servers = [(silent_addr.ip(), silent_addr.port())];
+
+        let start = Instant::now();
+        let (result, _) = Server::new().run(Resolution::ask(
+            &"google.com".parse().unwrap(),
+            QueryType::A,
+            Name::root(),
+            servers.to_vec(),
+            start + Duration::from_millis(200),
+            Trace::off(),
+        ));
+
+        assert_eq!(result.unwrap_err(), DnsError::Timeout);
+        assert!(start.elapsed() < DEFAULT_QUERY_TIMEOUT);
+    }
+
+    #[test]
+    fn should_share_lookup_in_flight() {
+        let server = Arc::new(Server::new());
+        let qname: Name = "google.com".parse().unwrap();
+        let inflight = Arc::new(Inflight::default());
+        server
+            .inflight
+            .lock()
+            .unwrap()
+            .insert((qname.clone(), QueryType::A), inflight.clone());
+
+        let handle = {
+            let server = server.clone();
+            let qname = qname.clone();
+            thread::spawn(move || server.recursive_lookup(&qname, QueryType::A))
+        };
+
+        let mut res_packet = Packet::new();
+        res_packet.answers.push(Record::A {
+            domain: qname,
+            ip: Ipv4Addr::new(142, 250, 4, 100),
+            ttl: 300,
+        });
+        *inflight.result.lock().unwrap() = Some(Ok(res_packet.clone()));
+        inflight.done.notify_all();
+
+        assert_eq!(handle.join().unwrap().unwrap().answers, res_packet.answers);
+    }
+
+    #[test]
+    fn should_wake_waiters_when_lookup_unwinds() {
+        let server = Arc::new(Server::new());
+        let key = ("google.com".parse::<Name>().unwrap(), QueryType::A);
+        let inflight = Arc::new(Inflight::default());
+        server
+            .inflight
+            .lock()
+            .unwrap()
+            .insert(key.clone(), inflight.clone());
+
+        let handle = {
+            let server = server.clone();
+            let qname = key.0.clone();
+            thread::spawn(move || server.recursive_lookup(&qname, QueryType::A))
+        };
+
+        // once the other thread holds the lookup, the leader goes away
+        // without a result
+        while Arc::strong_count(&inflight) < 3 {
+            thread::yield_now();
+        }
+        drop(InflightGuard {
+            server: &server,
+            key: key.clone(),
+            inflight,
+        });
+
+        assert_eq!(handle.join().unwrap().unwrap_err(), DnsError::Timeout);
+        assert!(server.inflight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_bound_recursions() {
+        let mut server = Server::new();
+        server.set_max_recursions(1);
+        let deadline = Instant::now() + Duration::from_millis(50);
+
+        let slot = server.recursion_slot(deadline).unwrap();
+        assert!(matches!(
+            server.recursion_slot(deadline),
+            Err(DnsError::Timeout)
+        ));
+
+        drop(slot);
+        assert!(server.recursion_slot(deadline).is_ok());
+    }
+
+    #[test]
+    fn should_follow_cname_chain() {
+        let server = Server::new();
+        let edge = Record::A {
+            domain: "edge.example.org".parse().unwrap(),
+            ip: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        };
+        let records = [
+            cname("www.example.com", "cdn.example.net"),
+            cname("cdn.example.net", "edge.example.org"),
+            edge.clone(),
+        ];
+        server
+            .cache
+            .lock()
+            .unwrap()
+            .insert(&records, Instant::now());
+
+        let res_packet = server
+            .recursive_lookup(&"www.example.com".parse().unwrap(), QueryType::A)
+            .unwrap();
+
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(res_packet.answers, records);
+    }
+
+    #[test]
+    fn should_give_up_on_cname_loop() {
+        let server = Server::new();
+        let records = [
+            cname("a.example.com", "b.example.com"),
+            cname("b.example.com", "a.example.com"),
+        ];
+        server
+            .cache
+            .lock()
+            .unwrap()
+            .insert(&records, Instant::now());
+
+        let res_packet = server
+            .recursive_lookup(&"a.example.com".parse().unwrap(), QueryType::A)
+            .unwrap();
+
+        assert_eq!(res_packet.header.response_code, ResponseCode::SERVFAIL);
+        assert_eq!(res_packet.answers, records);
+    }
+
+    #[test]
+    fn should_answer_nxdomain_from_cache() {
+        let server = Server::new();
+        let qname: Name = "nope.google.com".parse().unwrap();
+        let soa = Record::SOA {
+            domain: "google.com".parse().unwrap(),
+            mname: "ns1.google.com".parse().unwrap(),
+            rname: "dns-admin.google.com".parse().unwrap(),
+            serial: 1,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 60,
+        };
+        let mut rev_packet = Packet::new();
+        rev_packet.header.response_code = ResponseCode::NXDOMAIN;
+        rev_packet.authorities.push(soa.clone());
+        server.cache_negative(&rev_packet, &qname, None, &"google.com".parse().unwrap());
+
+        let res_packet = server.recursive_lookup(&qname, QueryType::A).unwrap();
+
+        assert_eq!(res_packet.header.response_code, ResponseCode::NXDOMAIN);
+        assert!(res_packet.answers.is_empty());
+        assert_eq!(res_packet.authorities, [soa]);
+    }
+
+    #[test]
+    fn should_answer_from_own_zone() {
+        let rec = Record::A {
+            domain: "www.example.com".parse().unwrap(),
+            ip: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 3600,
+        };
+        let mut zone = Zone::new("example.com".parse().unwrap());
+        zone.insert(rec.clone());
+        let mut server = Server::new();
+        server.add_zone(zone);
+
+        let res_packet = server.respond(
+            Server::query_packet(&"www.example.com".parse().unwrap(), QueryType::A).unwrap(),
+        );
+
+        assert!(res_packet.header.authoritative_answer);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(res_packet.answers, [rec]);
+
+        // an answer from upstream is never ours to vouch for
+        let mut rev_packet = Packet::new();
+        rev_packet.header.authoritative_answer = true;
+        let res_packet = server.build_response(
+            Server::query_packet(&"google.com".parse().unwrap(), QueryType::A).unwrap(),
+            Some(Ok(rev_packet)),
+        );
+        assert!(!res_packet.header.authoritative_answer);
+    }
+
+    #[test]
+    fn should_add_addresses_for_mx_exchanges() {
+        let mut res_packet = Packet::new();
+        res_packet.answers.push(Record::MX {
+            domain: "google.com".parse().unwrap(),
+            priority: 10,
+            host: "smtp.google.com".parse().unwrap(),
+            ttl: 300,
+        });
+        let smtp_a = Record::A {
+            domain: "smtp.google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(142, 250, 4, 27),
+            ttl: 300,
+        };
+        let smtp_aaaa = Record::AAAA {
+            domain: "smtp.google.com".parse().unwrap(),
+            ip: "2607:f8b0:4023:c0b::1b".parse().unwrap(),
+            ttl: 300,
+        };
+        let unrelated = Record::A {
+            domain: "www.google.com".parse().unwrap(),
+            ip: Ipv4Addr::new(142, 250, 4, 99),
+            ttl: 300,
+        };
+        let known = [smtp_a.clone(), unrelated, smtp_aaaa.clone(), smtp_a.clone()];
+
+        Server::add_additionals(&mut res_packet, &known);
+
+        assert_eq!(res_packet.additionals, [smtp_a, smtp_aaaa]);
+    }
 }
@@ -0,0 +1,175 @@
+use crate::dns::error::DnsError;
+use crate::dns::name::Name;
+use crate::dns::packet::Packet;
+use crate::dns::record::QueryType;
+use log::{debug, log_enabled, Level};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// One step of a recursive lookup, as given by `Server::trace_lookup`.
+#[derive(Clone, Debug)]
+pub enum Hop {
+    /// `qname` was in the cache, nobody had to be asked.
+    Cached { qname: Name, qtype: QueryType },
+    /// We asked `server`, one of the servers for `zone`, about `qname`.
+    /// `response` is the answer and its size in bytes, or why there was
+    /// none, `rtt` after we sent the query.
+    Query {
+        zone: Name,
+        server: IpAddr,
+        qname: Name,
+        qtype: QueryType,
+        rtt: Duration,
+        response: Result<(Packet, usize), DnsError>,
+    },
+    /// The last response handed the name down to `zone`, served by
+    /// `hosts`. `glue` is the addresses of theirs that came along.
+    Referral {
+        zone: Name,
+        hosts: Vec<Name>,
+        glue: Vec<IpAddr>,
+    },
+    /// The referral had no glue, so we looked up `host` ourselves: `hops`
+    /// is how, `addrs` what we got.
+    ResolveNs {
+        host: Name,
+        qtype: QueryType,
+        hops: Vec<Hop>,
+        addrs: Vec<IpAddr>,
+    },
+}
+
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hop::Cached { qname, qtype } => write!(f, "{} {} from cache", qname, qtype),
+            Hop::Query {
+                zone,
+                server,
+                qname,
+                qtype,
+                rtt,
+                response,
+            } => {
+                write!(f, "{} {} at {} ({}): ", qname, qtype, server, zone)?;
+                match response {
+                    Ok((res_packet, size)) => write!(
+                        f,
+                        "{}, {} bytes in {} ms",
+                        res_packet.header.response_code,
+                        size,
+                        rtt.as_millis()
+                    ),
+                    Err(e) => write!(f, "{} after {} ms", e, rtt.as_millis()),
+                }
+            }
+            Hop::Referral { zone, hosts, glue } if glue.is_empty() => {
+                write!(f, "referred to {}, NS {}, no glue", zone, join(hosts))
+            }
+            Hop::Referral { zone, hosts, glue } => write!(
+                f,
+                "referred to {}, NS {}, glue {}",
+                zone,
+                join(hosts),
+                join(glue)
+            ),
+            Hop::ResolveNs {
+                host, qtype, addrs, ..
+            } => write!(f, "resolved NS {} {}: {}", host, qtype, join(addrs)),
+        }
+    }
+}
+
+/// Where a lookup notes its hops. Unless it's being traced they only go to
+/// the debug log.
+pub(crate) struct Trace {
+    hops: Option<Vec<Hop>>,
+}
+
+impl Trace {
+    /// A trace that keeps every hop.
+    pub(crate) fn new() -> Trace {
+        Trace {
+            hops: Some(Vec::new()),
+        }
+    }
+
+    /// A trace that keeps nothing.
+    pub(crate) fn off() -> Trace {
+        Trace { hops: None }
+    }
+
+    /// A trace for a lookup nested in this one, kept if this one is.
+    pub(crate) fn nested(&self) -> Trace {
+        match self.hops {
+            Some(_) => Trace::new(),
+            None => Trace::off(),
+        }
+    }
+
+    /// Notes the hop `hop` builds, only building it if someone will see it.
+    pub(crate) fn record<F: FnOnce() -> Hop>(&mut self, hop: F) {
+        match self.hops.as_mut() {
+            Some(hops) => hops.push(hop()),
+            None if log_enabled!(Level::Debug) => debug!("{}", hop()),
+            None => {}
+        }
+    }
+
+    pub(crate) fn into_hops(self) -> Vec<Hop> {
+        self.hops.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::dns::header::ResponseCode;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn should_keep_hops_only_when_tracing() {
+        let hop = || Hop::Referral {
+            zone: "com".parse().unwrap(),
+            hosts: vec!["a.gtld-servers.net".parse().unwrap()],
+            glue: vec![IpAddr::V4(Ipv4Addr::new(192, 5, 6, 30))],
+        };
+
+        let mut trace = Trace::off();
+        trace.record(hop);
+        assert!(trace.nested().into_hops().is_empty());
+        assert!(trace.into_hops().is_empty());
+
+        let mut trace = Trace::new();
+        trace.record(hop);
+        let mut res_packet = Packet::new();
+        res_packet.header.response_code = ResponseCode::NXDOMAIN;
+        trace.record(|| Hop::Query {
+            zone: "com".parse().unwrap(),
+            server: IpAddr::V4(Ipv4Addr::new(192, 5, 6, 30)),
+            qname: "nope.com".parse().unwrap(),
+            qtype: QueryType::A,
+            rtt: Duration::from_millis(12),
+            response: Ok((res_packet, 100)),
+        });
+
+        let hops: Vec<String> = trace.into_hops().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            hops,
+            [
+                "referred to com., NS a.gtld-servers.net., glue 192.5.6.30",
+                "nope.com. A at 192.5.6.30 (com.): NXDOMAIN, 100 bytes in 12 ms"
+            ]
+        );
+    }
+}
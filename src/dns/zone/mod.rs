@@ -0,0 +1,5 @@
+mod parser;
+mod zone;
+
+pub use parser::ParseError;
+pub use zone::Zone;
@@ -0,0 +1,617 @@
+use crate::dns::name::Name;
+use crate::dns::record::Record;
+use crate::dns::zone::Zone;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How deep `$INCLUDE`s may nest, which also stops a file including itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// A mistake in a master file, and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub file: String,
+    /// Counting from 1, or 0 if the error is about the file as a whole.
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.msg)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.msg)
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Zone {
+    /// Reads the zone at `origin` from the master file at `path`
+    /// (RFC 1035 section 5). `$INCLUDE`d files are found relative to the
+    /// file including them.
+    pub fn load<P: AsRef<Path>>(path: P, origin: Name) -> Result<Zone, ParseError> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|e| ParseError {
+            file: file.clone(),
+            line: 0,
+            msg: e.to_string(),
+        })?;
+
+        let mut parser = Parser::new(origin.clone());
+        parser.read(&text, &file, path.parent(), origin)?;
+        parser.finish(&file)
+    }
+
+    /// Reads the zone at `origin` from master file text, `$INCLUDE`s being
+    /// relative to the working directory.
+    pub fn parse(text: &str, origin: Name) -> Result<Zone, ParseError> {
+        let mut parser = Parser::new(origin.clone());
+        parser.read(text, "-", None, origin)?;
+        parser.finish("-")
+    }
+}
+
+/// One record or directive, which parentheses may spread over lines.
+struct Entry {
+    line: usize,
+    /// Whether the entry starts with blanks, leaving out the owner.
+    blank_owner: bool,
+    tokens: Vec<String>,
+}
+
+/// Splits master file text into entries, dropping comments and the
+/// quotes round quoted strings.
+fn entries(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut line = 1;
+    let mut start = 1;
+    let mut depth = 0;
+    let mut blank_owner = text.starts_with([' ', '\t']);
+
+    let end_token = |token: &mut String, tokens: &mut Vec<String>| {
+        if !token.is_empty() {
+            tokens.push(std::mem::take(token));
+        }
+    };
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                token.push(c);
+                if let Some(c) = chars.next() {
+                    line += (c == '\n') as usize;
+                    token.push(c);
+                }
+            }
+            '"' => {
+                end_token(&mut token, &mut tokens);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            token.push('\\');
+                            token.extend(chars.next());
+                        }
+                        Some('\n') | None => return Err((line, "unclosed quote".to_string())),
+                        Some(c) => token.push(c),
+                    }
+                }
+                tokens.push(std::mem::take(&mut token));
+            }
+            ';' => while chars.next_if(|e| *e != '\n').is_some() {},
+            '(' => {
+                end_token(&mut token, &mut tokens);
+                depth += 1;
+            }
+            ')' => {
+                end_token(&mut token, &mut tokens);
+                if depth == 0 {
+                    return Err((line, "unbalanced )".to_string()));
+                }
+                depth -= 1;
+            }
+            '\n' => {
+                end_token(&mut token, &mut tokens);
+                line += 1;
+                if depth == 0 {
+                    if !tokens.is_empty() {
+                        entries.push(Entry {
+                            line: start,
+                            blank_owner,
+                            tokens: std::mem::take(&mut tokens),
+                        });
+                    }
+                    start = line;
+                    blank_owner = matches!(chars.peek(), Some(' ') | Some('\t'));
+                }
+            }
+            ' ' | '\t' | '\r' => end_token(&mut token, &mut tokens),
+            _ => token.push(c),
+        }
+    }
+
+    if depth > 0 {
+        return Err((start, "unclosed (".to_string()));
+    }
+    end_token(&mut token, &mut tokens);
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            line: start,
+            blank_owner,
+            tokens,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// A TTL in seconds, or with BIND's units, like `1h30m`.
+fn parse_ttl(s: &str) -> Option<u32> {
+    if !s.starts_with(|e: char| e.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut total: u32 = 0;
+    let mut n: u32 = 0;
+    for c in s.chars() {
+        let unit = match c.to_ascii_lowercase() {
+            c if c.is_ascii_digit() => {
+                n = n.checked_mul(10)?.checked_add(c.to_digit(10)?)?;
+                continue;
+            }
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(n.checked_mul(unit)?)?;
+        n = 0;
+    }
+
+    total.checked_add(n)
+}
+
+/// `s` in presentation form, relative to `origin` unless it ends in an
+/// unescaped dot. `@` is the origin itself.
+fn parse_name(s: &str, origin: &Name) -> Result<Name, String> {
+    if s == "@" {
+        return Ok(origin.clone());
+    }
+
+    let escapes =
+        s.trim_end_matches('.').len() - s.trim_end_matches('.').trim_end_matches('\\').len();
+    let absolute = s.ends_with('.') && escapes % 2 == 0;
+    let name: Name = s.parse().map_err(|e| format!("{}", e))?;
+    if absolute {
+        return Ok(name);
+    }
+
+    let labels: Vec<Vec<u8>> = name
+        .labels()
+        .chain(origin.labels())
+        .map(|e| e.to_vec())
+        .collect();
+    Name::from_labels(labels).map_err(|e| format!("{}", e))
+}
+
+fn parse_field<T: FromStr>(s: &str, what: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("bad {} {}", what, s))
+}
+
+/// The state carried from one entry to the next.
+struct Parser {
+    zone: Zone,
+    /// From `$TTL`, for records that don't give one.
+    default_ttl: Option<u32>,
+    /// The last TTL given, for records that don't give one without `$TTL`.
+    last_ttl: Option<u32>,
+    /// The last owner given, for records that leave it out.
+    last_owner: Option<Name>,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(origin: Name) -> Parser {
+        Parser {
+            zone: Zone::new(origin),
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+            depth: 0,
+        }
+    }
+
+    /// Reads the entries in `text`, from `file`. `$ORIGIN` only holds until
+    /// the end of the file it's in.
+    fn read(
+        &mut self,
+        text: &str,
+        file: &str,
+        dir: Option<&Path>,
+        mut origin: Name,
+    ) -> Result<(), ParseError> {
+        let at = |line: usize| {
+            move |msg: String| ParseError {
+                file: file.to_string(),
+                line,
+                msg,
+            }
+        };
+
+        let entries = entries(text).map_err(|(line, msg)| at(line)(msg))?;
+        for entry in entries.iter() {
+            match entry.tokens[0].as_str() {
+                "$INCLUDE" if !entry.blank_owner => {
+                    self.include(entry, dir, &origin).map_err(|e| match e {
+                        Ok(e) => e,
+                        Err(msg) => at(entry.line)(msg),
+                    })?;
+                }
+                _ => self.entry(entry, &mut origin).map_err(at(entry.line))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `$INCLUDE <file> [<origin>]`. Errors in the included file come back
+    /// as they are, anything wrong with the directive itself as a message.
+    fn include(
+        &mut self,
+        entry: &Entry,
+        dir: Option<&Path>,
+        origin: &Name,
+    ) -> Result<(), Result<ParseError, String>> {
+        let (path, origin) = match &entry.tokens[1..] {
+            [path] => (path, origin.clone()),
+            [path, name] => (path, parse_name(name, origin).map_err(Err)?),
+            _ => return Err(Err("expected $INCLUDE <file> [<origin>]".to_string())),
+        };
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(Err("too many nested $INCLUDEs".to_string()));
+        }
+
+        let path = match dir {
+            Some(dir) => dir.join(path),
+            None => Path::new(path).to_path_buf(),
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| Err(format!("can't include {}: {}", path.display(), e)))?;
+
+        self.depth += 1;
+        let result = self.read(&text, &path.display().to_string(), path.parent(), origin);
+        self.depth -= 1;
+        result.map_err(Ok)
+    }
+
+    fn entry(&mut self, entry: &Entry, origin: &mut Name) -> Result<(), String> {
+        let mut tokens = entry.tokens.iter().map(|e| e.as_str()).peekable();
+
+        if !entry.blank_owner {
+            match tokens.peek().copied() {
+                Some("$ORIGIN") => {
+                    return match &entry.tokens[1..] {
+                        [name] => {
+                            *origin = parse_name(name, origin)?;
+                            Ok(())
+                        }
+                        _ => Err("expected $ORIGIN <name>".to_string()),
+                    };
+                }
+                Some("$TTL") => {
+                    return match &entry.tokens[1..] {
+                        [ttl] => {
+                            self.default_ttl = Some(parse_ttl(ttl).ok_or("bad $TTL")?);
+                            Ok(())
+                        }
+                        _ => Err("expected $TTL <ttl>".to_string()),
+                    };
+                }
+                Some(e) if e.starts_with('$') => return Err(format!("unknown directive {}", e)),
+                _ => {}
+            }
+        }
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or("no owner to carry over from a record before")?
+        } else {
+            parse_name(tokens.next().unwrap(), origin)?
+        };
+
+        // [<TTL>] [<class>] in either order
+        let mut ttl = None;
+        while let Some(&token) = tokens.peek() {
+            if let Some(n) = parse_ttl(token) {
+                ttl = Some(n);
+            } else if token.eq_ignore_ascii_case("IN") {
+            } else if ["CH", "HS", "CS"]
+                .iter()
+                .any(|e| token.eq_ignore_ascii_case(e))
+            {
+                return Err(format!("unsupported class {}", token));
+            } else {
+                break;
+            }
+            tokens.next();
+        }
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL, and no $TTL or TTL before to default to")?;
+
+        let rtype = tokens.next().ok_or("expected a type")?.to_ascii_uppercase();
+        let rdata: Vec<&str> = tokens.collect();
+        let record = Parser::record(owner.clone(), ttl, &rtype, &rdata, origin)?;
+
+        if !self.zone.insert(record) {
+            return Err(format!(
+                "{} is outside the zone {}",
+                owner,
+                self.zone.origin()
+            ));
+        }
+        self.last_owner = Some(owner);
+
+        Ok(())
+    }
+
+    fn record(
+        domain: Name,
+        ttl: u32,
+        rtype: &str,
+        rdata: &[&str],
+        origin: &Name,
+    ) -> Result<Record, String> {
+        let fields = |n: usize| {
+            if rdata.len() == n {
+                Ok(())
+            } else {
+                Err(format!("{} takes {} fields, not {}", rtype, n, rdata.len()))
+            }
+        };
+        let time = |s: &str| parse_ttl(s).ok_or_else(|| format!("bad time {}", s));
+
+        let record = match rtype {
+            "A" => {
+                fields(1)?;
+                Record::A {
+                    domain,
+                    ip: parse_field(rdata[0], "IPv4 address")?,
+                    ttl,
+                }
+            }
+            "AAAA" => {
+                fields(1)?;
+                Record::AAAA {
+                    domain,
+                    ip: parse_field(rdata[0], "IPv6 address")?,
+                    ttl,
+                }
+            }
+            "NS" => {
+                fields(1)?;
+                Record::NS {
+                    domain,
+                    host: parse_name(rdata[0], origin)?,
+                    ttl,
+                }
+            }
+            "CNAME" => {
+                fields(1)?;
+                Record::CNAME {
+                    domain,
+                    host: parse_name(rdata[0], origin)?,
+                    ttl,
+                }
+            }
+            "MX" => {
+                fields(2)?;
+                Record::MX {
+                    domain,
+                    priority: parse_field(rdata[0], "preference")?,
+                    host: parse_name(rdata[1], origin)?,
+                    ttl,
+                }
+            }
+            "SOA" => {
+                fields(7)?;
+                Record::SOA {
+                    domain,
+                    mname: parse_name(rdata[0], origin)?,
+                    rname: parse_name(rdata[1], origin)?,
+                    serial: parse_field(rdata[2], "serial")?,
+                    refresh: time(rdata[3])?,
+                    retry: time(rdata[4])?,
+                    expire: time(rdata[5])?,
+                    minimum: time(rdata[6])?,
+                    ttl,
+                }
+            }
+            _ => return Err(format!("unsupported type {}", rtype)),
+        };
+
+        Ok(record)
+    }
+
+    /// The zone, once it's checked to have its SOA.
+    fn finish(self, file: &str) -> Result<Zone, ParseError> {
+        if self.zone.soa().is_none() {
+            return Err(ParseError {
+                file: file.to_string(),
+                line: 0,
+                msg: format!("no SOA record at {}", self.zone.origin()),
+            });
+        }
+        Ok(self.zone)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::dns::record::QueryType;
+    use std::net::Ipv4Addr;
+
+    fn origin() -> Name {
+        "example.com".parse().unwrap()
+    }
+
+    #[test]
+    fn should_parse_master_file() {
+        let text = "\
+$TTL 1h
+@   IN  SOA ns1 hostmaster.example.com. (
+            2024010101  ; serial
+            3600        ; refresh
+            10m         ; retry
+            1w          ; expire
+            300 )       ; minimum
+    IN  NS  ns1
+        MX  10 mail.example.com.
+ns1     A   192.0.2.1
+        AAAA 2001:db8::1
+mail 600 IN A 192.0.2.2
+www IN 60 CNAME @
+
+$ORIGIN sub.example.com.
+ns      A   192.0.2.3
+";
+        let zone = Zone::parse(text, origin()).unwrap();
+
+        match zone.soa() {
+            Some(Record::SOA {
+                mname,
+                serial,
+                retry,
+                expire,
+                ttl,
+                ..
+            }) => {
+                assert_eq!(*mname, "ns1.example.com");
+                assert_eq!(*serial, 2024010101);
+                assert_eq!(*retry, 600);
+                assert_eq!(*expire, 604800);
+                assert_eq!(*ttl, 3600);
+            }
+            e => panic!("expected a SOA, got {:?}", e),
+        }
+
+        let records: Vec<&Record> = zone.records().collect();
+        assert_eq!(records.len(), 8);
+        assert!(records.contains(&&Record::MX {
+            domain: origin(),
+            priority: 10,
+            host: "mail.example.com".parse().unwrap(),
+            ttl: 3600,
+        }));
+        assert!(records.contains(&&Record::A {
+            domain: "mail.example.com".parse().unwrap(),
+            ip: Ipv4Addr::new(192, 0, 2, 2),
+            ttl: 600,
+        }));
+        assert!(records.contains(&&Record::CNAME {
+            domain: "www.example.com".parse().unwrap(),
+            host: origin(),
+            ttl: 60,
+        }));
+        assert!(records
+            .iter()
+            .any(|e| e.domain() == Some(&"ns.sub.example.com".parse().unwrap())));
+        assert_eq!(
+            zone.lookup(&"ns1.example.com".parse().unwrap(), QueryType::AAAA)
+                .answers
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn should_default_ttl_to_last_one() {
+        let text = "\
+@ 300 SOA ns1 hostmaster 1 2 3 4 5
+  NS ns1
+";
+        let zone = Zone::parse(text, origin()).unwrap();
+
+        assert!(zone.records().all(|e| e.ttl() == 300));
+        assert!(zone.records().any(|e| *e
+            == Record::SOA {
+                domain: origin(),
+                mname: "ns1.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+                ttl: 300,
+            }));
+    }
+
+    #[test]
+    fn should_include_files() {
+        let dir = std::env::temp_dir().join(format!("learndns-zone-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("example.com.zone"),
+            "$TTL 300\n@ SOA ns1 hostmaster 1 2 3 4 5\n$INCLUDE hosts.zone sub\nwww A 192.0.2.2\n",
+        )
+        .unwrap();
+        fs::write(dir.join("hosts.zone"), "www A 192.0.2.1\n").unwrap();
+
+        let zone = Zone::load(dir.join("example.com.zone"), origin()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let www = |e: &str| zone.lookup(&e.parse().unwrap(), QueryType::A).answers;
+        assert_eq!(www("www.sub.example.com").len(), 1);
+        // the included file's origin doesn't leak back out
+        assert_eq!(www("www.example.com").len(), 1);
+    }
+
+    #[test]
+    fn should_report_file_and_line() {
+        let err = |text: &str| Zone::parse(text, origin()).unwrap_err();
+
+        let e = err("$TTL 300\n@ SOA ns1 hostmaster 1 2 3 4 5\nwww A 192.0.2\n");
+        assert_eq!((e.file.as_str(), e.line), ("-", 3));
+        assert_eq!(e.to_string(), "-:3: bad IPv4 address 192.0.2");
+
+        assert_eq!(err("@ 300 SOA ns1 hostmaster (\n1 2 3\n4 5\n").line, 1);
+        assert_eq!(err("$TTL 300\nwww.example.org. A 192.0.2.1\n").line, 2);
+        assert_eq!(err("$TTL 300\n@ TXT hello\n").line, 2);
+        assert_eq!(err("www A 192.0.2.1\n").line, 1);
+        assert_eq!(err("$TTL 300\nwww A 192.0.2.1\n").line, 0);
+    }
+
+    #[test]
+    fn should_read_names_relative_to_origin() {
+        let origin = origin();
+        assert_eq!(parse_name("@", &origin).unwrap(), origin);
+        assert_eq!(parse_name("www", &origin).unwrap(), "www.example.com");
+        assert_eq!(parse_name("www.", &origin).unwrap(), "www");
+        assert_eq!(
+            parse_name("a\\.", &origin).unwrap().labels().next(),
+            Some(&b"a."[..])
+        );
+        assert_eq!(parse_name("a\\.", &origin).unwrap().label_count(), 3);
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1x"), None);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::dns::header::ResponseCode;
+use crate::dns::name::Name;
+use crate::dns::packet::Packet;
+use crate::dns::record::{QueryType, Record};
+use crate::dns::server::MAX_CNAME_CHAIN;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The data of a zone we're authoritative for, kept in memory.
+///
+/// Records are grouped by owner name, in canonical order, so every name
+/// below one comes right after it.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    origin: Name,
+    records: BTreeMap<Name, Vec<Record>>,
+}
+
+impl Zone {
+    pub fn new(origin: Name) -> Zone {
+        Zone {
+            origin,
+            records: BTreeMap::new(),
+        }
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    /// Adds `record` to the zone. Returns false, leaving the zone as it
+    /// was, if it's OPT or its owner isn't in the zone.
+    pub fn insert(&mut self, record: Record) -> bool {
+        let domain = match record.domain() {
+            Some(domain) if domain.is_subdomain_of(&self.origin) => domain.clone(),
+            _ => return false,
+        };
+        let rrset = self.records.entry(domain).or_default();
+        if !rrset.contains(&record) {
+            rrset.push(record);
+        }
+        true
+    }
+
+    /// The SOA record at the apex.
+    pub fn soa(&self) -> Option<&Record> {
+        self.rrset(&self.origin, QueryType::SOA).next()
+    }
+
+    /// Every record in the zone, in canonical order of owner names.
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.values().flatten()
+    }
+
+    fn rrset<'a>(&'a self, name: &Name, qtype: QueryType) -> impl Iterator<Item = &'a Record> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |e| e.qtype() == qtype)
+    }
+
+    /// The closest zone cut between the apex and `qname`, if `qname` is in
+    /// a delegated child zone.
+    fn delegation(&self, qname: &Name) -> Option<Name> {
+        let depth = qname.label_count() - self.origin.label_count();
+        let mut below_apex: Vec<Name> = qname.ancestors().take(depth).collect();
+        below_apex.reverse();
+        below_apex
+            .into_iter()
+            .find(|e| self.rrset(e, QueryType::NS).next().is_some())
+    }
+
+    /// Whether anything owns `name` or a name below it.
+    fn exists(&self, name: &Name) -> bool {
+        self.records
+            .range(name.clone()..)
+            .next()
+            .is_some_and(|(e, _)| e.is_subdomain_of(name))
+    }
+
+    /// Answers `qname`, which must be in the zone, from the zone's data:
+    /// the RRset with any CNAMEs leading to it inside the zone, a referral
+    /// to a delegated child with its glue, or NXDOMAIN or NODATA with the
+    /// SOA in the authority section. Everything but referrals is marked
+    /// authoritative.
+    pub fn lookup(&self, qname: &Name, qtype: QueryType) -> Packet {
+        let mut res_packet = Packet::new();
+        res_packet.header.query_response = true;
+        res_packet.header.authoritative_answer = true;
+
+        let mut name = qname.clone();
+        loop {
+            if let Some(cut) = self.delegation(&name) {
+                if res_packet.answers.is_empty() {
+                    res_packet.header.authoritative_answer = false;
+                    res_packet.authorities = self.rrset(&cut, QueryType::NS).cloned().collect();
+                    self.add_glue(&mut res_packet);
+                }
+                return res_packet;
+            }
+
+            let answers: Vec<Record> = self.rrset(&name, qtype).cloned().collect();
+            if !answers.is_empty() {
+                res_packet.answers.extend(answers);
+                self.add_glue(&mut res_packet);
+                return res_packet;
+            }
+
+            let cname = self.rrset(&name, QueryType::CNAME).next();
+            match cname {
+                Some(Record::CNAME { host, .. })
+                    if res_packet.answers.len() < MAX_CNAME_CHAIN
+                        && !res_packet.answers.iter().any(|e| e.domain() == Some(host)) =>
+                {
+                    res_packet.answers.push(cname.unwrap().clone());
+                    if !host.is_subdomain_of(&self.origin) {
+                        // the client has to look the rest up elsewhere
+                        return res_packet;
+                    }
+                    name = host.clone();
+                }
+                Some(cname) => {
+                    res_packet.answers.push(cname.clone());
+                    return res_packet;
+                }
+                None => break,
+            }
+        }
+
+        if !self.exists(&name) {
+            res_packet.header.response_code = ResponseCode::NXDOMAIN;
+        }
+        // RFC 2308, negative answers may be cached for the SOA's minimum
+        if let Some(soa) = self.soa() {
+            let mut soa = soa.clone();
+            if let Record::SOA { minimum, ttl, .. } = &mut soa {
+                *ttl = (*ttl).min(*minimum);
+            }
+            res_packet.authorities.push(soa);
+        }
+
+        res_packet
+    }
+
+    /// Writes the zone out as a master file that `Zone::load` reads back.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Adds addresses from the zone for the hosts the response names, glue
+    /// for referrals included.
+    fn add_glue(&self, res_packet: &mut Packet) {
+        let mut glue = Vec::new();
+        for host in res_packet.additional_hosts() {
+            for qtype in [QueryType::A, QueryType::AAAA].iter() {
+                glue.extend(self.rrset(host, *qtype).cloned());
+            }
+        }
+        res_packet.additionals.extend(glue);
+    }
+}
+
+impl fmt::Display for Zone {
+    /// The zone as a master file: `$ORIGIN`, the SOA, then every other
+    /// record in canonical order, all with absolute names.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "$ORIGIN {}", self.origin)?;
+        if let Some(soa) = self.soa() {
+            writeln!(f, "{}", soa)?;
+        }
+        for rec in self.records().filter(|e| e.qtype() != QueryType::SOA) {
+            writeln!(f, "{}", rec)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn zone() -> Zone {
+        let name = |e: &str| -> Name { e.parse().unwrap() };
+        let mut zone = Zone::new(name("example.com"));
+        let records = vec![
+            Record::SOA {
+                domain: name("example.com"),
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl: 3600,
+            },
+            Record::NS {
+                domain: name("example.com"),
+                host: name("ns1.example.com"),
+                ttl: 3600,
+            },
+            Record::A {
+                domain: name("ns1.example.com"),
+                ip: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 3600,
+            },
+            Record::MX {
+                domain: name("example.com"),
+                priority: 10,
+                host: name("mail.example.com"),
+                ttl: 3600,
+            },
+            Record::A {
+                domain: name("mail.example.com"),
+                ip: Ipv4Addr::new(192, 0, 2, 2),
+                ttl: 3600,
+            },
+            Record::A {
+                domain: name("www.a.b.example.com"),
+                ip: Ipv4Addr::new(192, 0, 2, 3),
+                ttl: 3600,
+            },
+            Record::CNAME {
+                domain: name("web.example.com"),
+                host: name("www.a.b.example.com"),
+                ttl: 3600,
+            },
+            Record::NS {
+                domain: name("sub.example.com"),
+                host: name("ns.sub.example.com"),
+                ttl: 3600,
+            },
+            Record::A {
+                domain: name("ns.sub.example.com"),
+                ip: Ipv4Addr::new(192, 0, 2, 4),
+                ttl: 3600,
+            },
+        ];
+        for rec in records {
+            assert!(zone.insert(rec));
+        }
+        zone
+    }
+
+    #[test]
+    fn should_answer_with_additionals() {
+        let res_packet = zone().lookup(&"example.com".parse().unwrap(), QueryType::MX);
+
+        assert!(res_packet.header.authoritative_answer);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert_eq!(res_packet.answers.len(), 1);
+        assert_eq!(
+            res_packet.additionals[0].domain(),
+            Some(&"mail.example.com".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn should_follow_cname_in_zone() {
+        let res_packet = zone().lookup(&"web.example.com".parse().unwrap(), QueryType::A);
+
+        assert!(res_packet.header.authoritative_answer);
+        assert_eq!(res_packet.answers.len(), 2);
+        assert_eq!(res_packet.answers[0].qtype(), QueryType::CNAME);
+        assert_eq!(res_packet.answers[1].qtype(), QueryType::A);
+    }
+
+    #[test]
+    fn should_refer_to_child_zone() {
+        let res_packet = zone().lookup(&"www.sub.example.com".parse().unwrap(), QueryType::A);
+
+        assert!(!res_packet.header.authoritative_answer);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert!(res_packet.answers.is_empty());
+        assert_eq!(res_packet.authorities[0].qtype(), QueryType::NS);
+        assert_eq!(
+            res_packet.additionals[0].domain(),
+            Some(&"ns.sub.example.com".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn should_answer_nxdomain_and_nodata() {
+        let zone = zone();
+
+        let res_packet = zone.lookup(&"nope.example.com".parse().unwrap(), QueryType::A);
+        assert!(res_packet.header.authoritative_answer);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NXDOMAIN);
+        assert_eq!(res_packet.authorities[0].qtype(), QueryType::SOA);
+        assert_eq!(res_packet.authorities[0].ttl(), 300);
+
+        let res_packet = zone.lookup(&"mail.example.com".parse().unwrap(), QueryType::AAAA);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+        assert!(res_packet.answers.is_empty());
+        assert_eq!(res_packet.authorities[0].qtype(), QueryType::SOA);
+
+        // an empty non-terminal exists, it just owns nothing
+        let res_packet = zone.lookup(&"b.example.com".parse().unwrap(), QueryType::A);
+        assert_eq!(res_packet.header.response_code, ResponseCode::NOERROR);
+    }
+
+    #[test]
+    fn should_dump_parseable_master_file() {
+        let zone = zone();
+        let text = zone.to_string();
+
+        assert!(text.starts_with(
+            "$ORIGIN example.com.\nexample.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 300\n"
+        ));
+        let parsed = Zone::parse(&text, zone.origin().clone()).unwrap();
+        assert_eq!(
+            parsed.records().collect::<Vec<_>>(),
+            zone.records().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_reject_records_outside_zone() {
+        let mut zone = zone();
+        assert!(!zone.insert(Record::A {
+            domain: "example.org".parse().unwrap(),
+            ip: Ipv4Addr::new(192, 0, 2, 5),
+            ttl: 3600,
+        }));
+    }
+}
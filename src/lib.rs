@@ -0,0 +1,4 @@
+#![allow(clippy::upper_case_acronyms, clippy::module_inception)]
+
+pub mod config;
+pub mod dns;
@@ -1,15 +1,129 @@
-mod dns;
-use crate::dns::server::Server;
-use std::net::UdpSocket;
+use learndns::config::Config;
+use learndns::dns::packet::MAX_EDNS_SIZE;
+use learndns::dns::pool::ThreadPool;
+use learndns::dns::server::Server;
+use log::{error, info, warn, Log, Metadata, Record};
+use std::env;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Threads answering UDP queries, and how many queries may wait for one.
+const UDP_WORKERS: usize = 64;
+const UDP_QUEUE: usize = 1024;
+
+/// Threads serving TCP connections, and how many connections may wait for
+/// one.
+const TCP_WORKERS: usize = 128;
+const TCP_QUEUE: usize = 128;
+
+/// Writes log lines to stderr.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("{:5} {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
 
 fn main() {
-    let socket = UdpSocket::bind(("0.0.0.0", 4053)).unwrap();
-    loop {
-        match Server::handle_query(&socket) {
-            Ok(_) => {}
+    // e.g. `learndns --config learndns.toml --listen [::]:4053`
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("learndns: {}", e);
+            process::exit(2);
+        }
+    };
+
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(config.log_level);
+
+    let server = match config.server() {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("learndns: {}", e);
+            process::exit(2);
+        }
+    };
+    match server.prime() {
+        Ok(()) => info!("primed the root servers"),
+        Err(e) => warn!("priming failed, starting from the root hints: {}", e),
+    }
+    let pool = Arc::new(ThreadPool::new(UDP_WORKERS, UDP_QUEUE));
+    let tcp_pool = Arc::new(ThreadPool::new(TCP_WORKERS, TCP_QUEUE));
+
+    let mut handles = Vec::new();
+    for &addr in config.listen.iter() {
+        let (listener, socket) = match (TcpListener::bind(addr), UdpSocket::bind(addr)) {
+            (Ok(listener), Ok(socket)) => (listener, socket),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("learndns: can't listen on {}: {}", addr, e);
+                process::exit(1);
+            }
+        };
+        info!("listening on {}", addr);
+        handles.push(serve_tcp(server.clone(), tcp_pool.clone(), listener));
+        handles.push(serve_udp(server.clone(), pool.clone(), socket));
+    }
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+fn serve_tcp(server: Arc<Server>, pool: Arc<ThreadPool>, listener: TcpListener) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    let peer = stream.peer_addr();
+                    let queued = pool.try_execute(move || {
+                        if let Err(msg) = server.handle_tcp_connection(stream) {
+                            warn!("handle tcp err: {:?}", msg);
+                        }
+                    });
+                    if !queued {
+                        warn!("too many tcp connections, dropped one from {:?}", peer);
+                    }
+                }
+                Err(msg) => {
+                    error!("accept err: {:?}", msg);
+                }
+            }
+        }
+    })
+}
+
+fn serve_udp(server: Arc<Server>, pool: Arc<ThreadPool>, socket: UdpSocket) -> JoinHandle<()> {
+    let socket = Arc::new(socket);
+    thread::spawn(move || loop {
+        let mut req_data = [0; MAX_EDNS_SIZE];
+        let (len, src): (usize, SocketAddr) = match socket.recv_from(&mut req_data) {
+            Ok(v) => v,
             Err(msg) => {
-                println!("handle err: {:?}", msg);
+                error!("recv err: {:?}", msg);
+                continue;
             }
+        };
+
+        let server = server.clone();
+        let udp_socket = socket.clone();
+        let queued = pool.try_execute(move || {
+            if let Err(msg) = server.handle_datagram(&udp_socket, &req_data[..len], src) {
+                warn!("handle err: {:?}", msg);
+            }
+        });
+        if !queued {
+            warn!("too many queries waiting, dropped one from {}", src);
         }
-    }
+    })
 }